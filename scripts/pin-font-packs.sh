@@ -0,0 +1,31 @@
+#!/bin/bash
+# Usage: ./scripts/pin-font-packs.sh
+# Downloads every font pack file from its upstream URL in src/fontpack.rs and
+# prints its SHA-256, for the `sha256` pins in FONT_PACKS.
+
+set -euo pipefail
+
+SCRIPT_DIR="$(cd "$(dirname "$0")" && pwd)"
+ROOT="$(dirname "$SCRIPT_DIR")"
+
+# noto_cjk!(tag, style, dir, file, sha256) expands to the URL below; other
+# files spell out their `url`
+urls=$(perl -0777 -ne '
+  while (/noto_cjk!\(\s*"([^"]+)",\s*"([^"]+)",\s*"([^"]+)",\s*"([^"]+)"/g) {
+    print "https://github.com/notofonts/noto-cjk/raw/$1/$2/OTF/$3/$4\n";
+  }
+  while (/url: "(https:[^"]+)"/g) {
+    print "$1\n";
+  }
+' "$ROOT/src/fontpack.rs")
+
+TMP="$(mktemp -d)"
+trap 'rm -rf "$TMP"' EXIT
+
+for url in $urls; do
+  name="${url##*/}"
+  curl -fsSL --retry 3 -o "$TMP/$name" "$url"
+  hash=$(sha256sum "$TMP/$name" | cut -d' ' -f1)
+  echo "$hash  $name"
+  rm "$TMP/$name"
+done
@@ -1,286 +1,997 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::OnceLock;
+use std::sync::{Mutex, OnceLock};
 
-use typst::diag::{FileError, FileResult};
-use typst::foundations::{Bytes, Datetime};
+use typst::diag::{FileError, FileResult, SourceDiagnostic};
+use typst::foundations::{Bytes, Datetime, Smart};
 use typst::layout::PagedDocument;
 use typst::syntax::{FileId, Source, VirtualPath};
-use typst::text::{Font, FontBook, FontInfo};
+use typst::text::{Font, FontBook};
 use typst::utils::LazyHash;
 use typst::{Library, World};
 
-use typst_pdf::PdfOptions;
+use typst_pdf::{PdfOptions, PdfStandards, Timestamp};
+
+use crate::convert::{Asset, AssetSource};
+use crate::diagnostic::{Diagnostic, Severity, SourceMap, SourcePosition, Stage};
+use crate::fonts::FontSet;
+use crate::package::PackageStore;
+use crate::template::{FontFallbacks, TEMPLATE_MOUNT, files_in};
+
+/// Default resolution of PNG output.
+pub const DEFAULT_PPI: f32 = 144.0;
+
+/// Substituted for remote images that cannot be fetched in offline mode.
+const PLACEHOLDER_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="320" height="180" viewBox="0 0 320 180"><rect x="1" y="1" width="318" height="178" fill="#f5f5f5" stroke="#9e9e9e" stroke-width="2" stroke-dasharray="8 6"/><path d="M1 1 L319 179 M319 1 L1 179" stroke="#bdbdbd" stroke-width="1.5"/></svg>"##;
 
 #[derive(Debug)]
 pub enum CompileError {
     Io(std::io::Error),
-    Typst(String),
+    /// Typst rejected the document; at least one diagnostic is an error.
+    Typst(Vec<Diagnostic>),
 }
 
 impl Display for CompileError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Io(err) => write!(f, "I/O error: {err}"),
-            Self::Typst(msg) => write!(f, "typst error: {msg}"),
+            Self::Typst(diagnostics) => {
+                write!(f, "typst error: ")?;
+                for (index, diagnostic) in diagnostics.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{diagnostic}")?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
 impl std::error::Error for CompileError {}
 
-pub fn compile_typst_to_pdf(source: &str, output_path: &Path) -> Result<Vec<u8>, CompileError> {
-    let world = MdxportWorld::new(source);
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Pdf,
+    Png,
+    Svg,
+}
 
-    let warned = typst::compile::<PagedDocument>(&world);
-    let document = warned.output.map_err(|diagnostics| {
-        let messages: Vec<String> = diagnostics
-            .iter()
-            .map(|d| {
-                let span_info = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-                    d.span
-                        .id()
-                        .and_then(|id| world.source(id).ok())
-                        .and_then(|src| {
-                            let range = src.range(d.span)?;
-                            let line = src.byte_to_line(range.start)?;
-                            Some(format!(" (line {})", line + 1))
-                        })
-                }))
-                .ok()
-                .flatten()
-                .unwrap_or_default();
-                format!("{}{span_info}", d.message)
-            })
-            .collect();
-        CompileError::Typst(messages.join("\n"))
-    })?;
+#[derive(Debug, Clone)]
+pub struct OutputFormatParseError {
+    value: String,
+}
 
-    let options = PdfOptions::default();
-    let pdf_bytes = typst_pdf::pdf(&document, &options).map_err(|diagnostics| {
-        let messages: Vec<String> = diagnostics.iter().map(|d| d.message.to_string()).collect();
-        CompileError::Typst(messages.join("\n"))
-    })?;
+impl Display for OutputFormatParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unsupported output format: {}", self.value)
+    }
+}
 
-    if let Some(parent) = output_path.parent()
-        && !parent.as_os_str().is_empty()
-    {
-        fs::create_dir_all(parent).map_err(CompileError::Io)?;
+impl std::error::Error for OutputFormatParseError {}
+
+impl TryFrom<&str> for OutputFormat {
+    type Error = OutputFormatParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "pdf" => Ok(Self::Pdf),
+            "png" => Ok(Self::Png),
+            "svg" => Ok(Self::Svg),
+            _ => Err(OutputFormatParseError {
+                value: value.to_string(),
+            }),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Infer the format from a file extension, e.g. `out.png`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        Self::try_from(ext).ok()
     }
-    fs::write(output_path, &pdf_bytes).map_err(CompileError::Io)?;
 
-    Ok(pdf_bytes)
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Pdf => "pdf",
+            Self::Png => "png",
+            Self::Svg => "svg",
+        }
+    }
 }
 
-// ---------------------------------------------------------------------------
-// World implementation
-// ---------------------------------------------------------------------------
+/// PDF standard the output must conform to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PdfStandard {
+    /// Plain PDF 1.7.
+    #[default]
+    V1_7,
+    /// PDF/A-2b, for archival.
+    A2b,
+    /// PDF/A-3b, like A-2b but allowing arbitrary embedded files.
+    A3b,
+}
 
-struct MdxportWorld {
-    library: LazyHash<Library>,
-    main_id: FileId,
-    main_source: Source,
-    font_storage: &'static FontStorage,
+#[derive(Debug, Clone)]
+pub struct PdfStandardParseError {
+    value: String,
 }
 
-impl MdxportWorld {
-    fn new(source: &str) -> Self {
-        let main_id = FileId::new(None, VirtualPath::new("/main.typ"));
-        let main_source = Source::new(main_id, source.to_string());
+impl Display for PdfStandardParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unsupported PDF standard: {}", self.value)
+    }
+}
 
-        Self {
-            library: LazyHash::new(Library::default()),
-            main_id,
-            main_source,
-            font_storage: FontStorage::global(),
+impl std::error::Error for PdfStandardParseError {}
+
+impl TryFrom<&str> for PdfStandard {
+    type Error = PdfStandardParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "1.7" => Ok(Self::V1_7),
+            "a-2b" => Ok(Self::A2b),
+            "a-3b" => Ok(Self::A3b),
+            _ => Err(PdfStandardParseError {
+                value: value.to_string(),
+            }),
         }
     }
 }
 
-impl World for MdxportWorld {
-    fn library(&self) -> &LazyHash<Library> {
-        &self.library
+impl Display for PdfStandard {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::V1_7 => write!(f, "PDF 1.7"),
+            Self::A2b => write!(f, "PDF/A-2b"),
+            Self::A3b => write!(f, "PDF/A-3b"),
+        }
     }
+}
 
-    fn book(&self) -> &LazyHash<FontBook> {
-        &self.font_storage.book
+impl PdfStandard {
+    fn to_typst(self) -> typst_pdf::PdfStandard {
+        match self {
+            Self::V1_7 => typst_pdf::PdfStandard::V_1_7,
+            Self::A2b => typst_pdf::PdfStandard::A_2b,
+            Self::A3b => typst_pdf::PdfStandard::A_3b,
+        }
     }
+}
 
-    fn main(&self) -> FileId {
-        self.main_id
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Files (e.g. images) the document references, served by virtual path.
+    pub assets: Vec<Asset>,
+    /// Only use cached remote images; uncached ones become placeholders.
+    /// Typst packages are not downloaded either.
+    pub offline: bool,
+    /// Cache directory for remote images. `None` = `~/.mdxport/cache`.
+    pub cache_dir: Option<PathBuf>,
+    /// Export format used by `compile_typst_to_format` / `compile_typst_to_files`.
+    pub format: OutputFormat,
+    /// Pixels per inch for PNG output. `None` = `DEFAULT_PPI`.
+    pub ppi: Option<f32>,
+    /// Stable identifier for the PDF document ID, e.g. from
+    /// [`document_ident`]. `None` = derived from title and authors.
+    pub ident: Option<String>,
+    /// Current time in seconds since the Unix epoch, used by
+    /// `datetime.today()` and as the PDF creation date when the document sets
+    /// none. Pinning it makes output reproducible. `None` = `SOURCE_DATE_EPOCH`
+    /// if set, otherwise the system clock.
+    pub timestamp: Option<i64>,
+    /// PDF standard to enforce, e.g. PDF/A-2b for archival.
+    pub pdf_standard: PdfStandard,
+    /// Maps offsets in the Typst source to Markdown positions, so
+    /// diagnostics point at the Markdown. See [`SourceMap::shifted`].
+    pub source_map: Option<SourceMap>,
+    /// Markdown file `source_map` refers to, recorded in diagnostics.
+    pub source_path: Option<PathBuf>,
+    /// Fonts available to the document. `None` = bundled and system fonts,
+    /// see [`FontSet::default`].
+    pub fonts: Option<FontSet>,
+    /// Directories searched for `@namespace/name:version` packages before
+    /// `MDXPORT_PACKAGE_PATH` and Typst's package directories. Missing
+    /// `@preview` packages are downloaded into Typst's package cache unless
+    /// `offline`.
+    pub package_paths: Vec<PathBuf>,
+    /// Directory of a multi-file custom template, served under
+    /// [`TEMPLATE_MOUNT`](crate::template::TEMPLATE_MOUNT). See
+    /// [`CustomTemplate`](crate::template::CustomTemplate).
+    pub template_dir: Option<PathBuf>,
+}
+
+/// Compilation output together with the warnings Typst reported.
+#[derive(Debug, Clone)]
+pub struct Compiled<T> {
+    pub output: T,
+    pub warnings: Vec<Diagnostic>,
+    /// Pages in the document, whatever the number of output buffers.
+    pub pages: usize,
+}
+
+/// Compile `source` to a PDF at `output_path`. Warnings are discarded; use
+/// [`compile_typst_to_pdf_with_options`] to receive them.
+pub fn compile_typst_to_pdf(source: &str, output_path: &Path) -> Result<Vec<u8>, CompileError> {
+    compile_typst_to_pdf_with_options(source, &CompileOptions::default(), output_path)
+        .map(|compiled| compiled.output)
+}
+
+pub fn compile_typst_to_pdf_with_options(
+    source: &str,
+    options: &CompileOptions,
+    output_path: &Path,
+) -> Result<Compiled<Vec<u8>>, CompileError> {
+    let compiled = compile_typst_to_bytes(source, options)?;
+    write_output(output_path, &compiled.output)?;
+    Ok(compiled)
+}
+
+/// Compile `source` to PDF bytes without touching the filesystem (apart
+/// from reading the assets it references).
+pub fn compile_typst_to_bytes(
+    source: &str,
+    options: &CompileOptions,
+) -> Result<Compiled<Vec<u8>>, CompileError> {
+    let world = MdxportWorld::new(source, options);
+    let (document, warnings) = compile_document(&world)?;
+    Ok(Compiled {
+        output: export_pdf(&world, &document, options)?,
+        warnings,
+        pages: document.pages.len(),
+    })
+}
+
+/// Compile `source` and export it as `options.format`: a single buffer for
+/// PDF, one buffer per page for PNG and SVG.
+pub fn compile_typst_to_format(
+    source: &str,
+    options: &CompileOptions,
+) -> Result<Compiled<Vec<Vec<u8>>>, CompileError> {
+    let world = MdxportWorld::new(source, options);
+    let (document, warnings) = compile_document(&world)?;
+    let output = export_format(&world, &document, options)?;
+    Ok(Compiled {
+        output,
+        warnings,
+        pages: document.pages.len(),
+    })
+}
+
+/// How many watch cycles an unused memoized result survives, as in
+/// `typst watch`.
+const MEMO_MAX_AGE: usize = 10;
+
+/// Ages out memoized results unused for the last [`MEMO_MAX_AGE`] calls.
+/// Call once per batch of rebuilds, not per document: each call ages the
+/// cached layout of every other watched document too.
+pub fn evict_memoized() {
+    typst::comemo::evict(MEMO_MAX_AGE);
+}
+
+/// Compiles successive versions of one document, keeping the Typst world
+/// alive between them. The main source is edited in place, so unchanged
+/// parts keep their spans and comemo reuses their layout; this is what makes
+/// watch-mode rebuilds of large documents fast.
+pub struct IncrementalCompiler {
+    world: Option<MdxportWorld>,
+}
+
+impl IncrementalCompiler {
+    pub fn new() -> Self {
+        Self { world: None }
     }
 
-    fn source(&self, id: FileId) -> FileResult<Source> {
-        if id == self.main_id {
-            Ok(self.main_source.clone())
-        } else {
-            Err(FileError::NotFound(id.vpath().as_rootless_path().into()))
-        }
+    /// Like [`compile_typst_to_format`], reusing work from earlier calls.
+    /// Assets are re-read on every call, so edited images are picked up.
+    /// Memoized results are kept until [`evict_memoized`] is called.
+    pub fn compile(
+        &mut self,
+        source: &str,
+        options: &CompileOptions,
+    ) -> Result<Compiled<Vec<Vec<u8>>>, CompileError> {
+        let world = match &mut self.world {
+            Some(world) => {
+                world.update(source, options);
+                world
+            }
+            None => self.world.insert(MdxportWorld::new(source, options)),
+        };
+        let (document, warnings) = compile_document(world)?;
+        let output = export_format(world, &document, options)?;
+        Ok(Compiled {
+            output,
+            warnings,
+            pages: document.pages.len(),
+        })
     }
+}
 
-    fn file(&self, id: FileId) -> FileResult<Bytes> {
-        Err(FileError::NotFound(id.vpath().as_rootless_path().into()))
+impl Default for IncrementalCompiler {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    fn font(&self, index: usize) -> Option<Font> {
-        self.font_storage.fonts.get(index)?.get()
+fn export_format(
+    world: &MdxportWorld,
+    document: &PagedDocument,
+    options: &CompileOptions,
+) -> Result<Vec<Vec<u8>>, CompileError> {
+    Ok(match options.format {
+        OutputFormat::Pdf => vec![export_pdf(world, document, options)?],
+        OutputFormat::Png => {
+            let pixel_per_pt = options.ppi.unwrap_or(DEFAULT_PPI) / 72.0;
+            document
+                .pages
+                .iter()
+                .map(|page| {
+                    typst_render::render(page, pixel_per_pt)
+                        .encode_png()
+                        .map_err(|err| CompileError::Io(std::io::Error::other(err)))
+                })
+                .collect::<Result<_, _>>()?
+        }
+        OutputFormat::Svg => document
+            .pages
+            .iter()
+            .map(|page| typst_svg::svg(page).into_bytes())
+            .collect(),
+    })
+}
+
+/// Compile `source` as `options.format` and write it to `output_path`.
+/// Multi-page PNG/SVG output is written to `name-1.png`, `name-2.png`, ….
+/// Returns each written path with its size in bytes.
+pub fn compile_typst_to_files(
+    source: &str,
+    options: &CompileOptions,
+    output_path: &Path,
+) -> Result<Compiled<Vec<(PathBuf, usize)>>, CompileError> {
+    let Compiled {
+        output,
+        warnings,
+        pages,
+    } = compile_typst_to_format(source, options)?;
+    let paths = page_output_paths(output_path, output.len());
+
+    let mut written = Vec::with_capacity(output.len());
+    for (path, bytes) in paths.into_iter().zip(output) {
+        write_output(&path, &bytes)?;
+        written.push((path, bytes.len()));
     }
+    Ok(Compiled {
+        output: written,
+        warnings,
+        pages,
+    })
+}
 
-    fn today(&self, _offset: Option<i64>) -> Option<Datetime> {
-        None
+/// Paths for `pages` output files: `output_path` itself for a single file,
+/// otherwise `stem-1.ext`, `stem-2.ext`, ….
+pub fn page_output_paths(output_path: &Path, pages: usize) -> Vec<PathBuf> {
+    if pages == 1 {
+        return vec![output_path.to_path_buf()];
     }
+
+    let stem = output_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = output_path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+
+    (1..=pages)
+        .map(|page| output_path.with_file_name(format!("{stem}-{page}{ext}")))
+        .collect()
 }
 
-// ---------------------------------------------------------------------------
-// Font loading (cached globally, loaded once)
-// ---------------------------------------------------------------------------
+/// Write `source` as `main.typ` into `dir` along with every asset it
+/// references and the template directory, at the paths the source uses, so
+/// that `typst compile main.typ` builds the directory unchanged. Returns the
+/// written files.
+pub fn export_typst_project(
+    source: &str,
+    options: &CompileOptions,
+    dir: &Path,
+) -> Result<Vec<PathBuf>, CompileError> {
+    let world = MdxportWorld::new(source, options);
+
+    let main_path = dir.join("main.typ");
+    write_output(&main_path, source.as_bytes())?;
+    let mut written = vec![main_path];
+
+    for asset in &options.assets {
+        let bytes = world.load(&asset.source).map_err(|err| {
+            CompileError::Io(std::io::Error::other(format!(
+                "{}: {err}",
+                asset.virtual_path
+            )))
+        })?;
+        let path = dir.join(asset.virtual_path.trim_start_matches('/'));
+        write_output(&path, bytes.as_slice())?;
+        written.push(path);
+    }
+
+    if let Some(template_dir) = &options.template_dir {
+        let mount = dir.join(TEMPLATE_MOUNT.trim_start_matches('/'));
+        for file in files_in(template_dir) {
+            let Ok(relative) = file.strip_prefix(template_dir) else {
+                continue;
+            };
+            let path = mount.join(relative);
+            write_output(&path, &fs::read(&file).map_err(CompileError::Io)?)?;
+            written.push(path);
+        }
+    }
 
-struct FontSlot {
-    data: Bytes,
-    index: u32,
-    font: OnceLock<Option<Font>>,
+    Ok(written)
 }
 
-impl FontSlot {
-    fn new(data: Bytes, index: u32) -> Self {
-        Self {
-            data,
-            index,
-            font: OnceLock::new(),
+fn compile_document(
+    world: &MdxportWorld,
+) -> Result<(PagedDocument, Vec<Diagnostic>), CompileError> {
+    let warned = typst::compile::<PagedDocument>(world);
+    let text = world.main_source.text();
+    let builtin = crate::template::builtin_template_len(text)
+        .map(|len| (len, FontFallbacks::of_template(&text[..len])));
+    let mut font_lists = Vec::new();
+    let mut warnings = Vec::new();
+    for warning in &warned.warnings {
+        match builtin_font_lists(world, builtin.as_ref(), warning) {
+            Some(lists) => font_lists.extend(lists),
+            None => warnings.push(to_diagnostic(world, warning)),
         }
     }
+    warnings.extend(unresolved_font_lists(world.book(), &font_lists));
+    let document = warned.output.map_err(|diagnostics| {
+        CompileError::Typst(
+            diagnostics
+                .iter()
+                .map(|diagnostic| to_diagnostic(world, diagnostic))
+                .collect(),
+        )
+    })?;
+    Ok((document, warnings))
+}
 
-    fn get(&self) -> Option<Font> {
-        self.font
-            .get_or_init(|| Font::new(self.data.clone(), self.index))
-            .clone()
+fn export_pdf(
+    world: &MdxportWorld,
+    document: &PagedDocument,
+    options: &CompileOptions,
+) -> Result<Vec<u8>, CompileError> {
+    let standards = PdfStandards::new(&[options.pdf_standard.to_typst()]).map_err(|err| {
+        CompileError::Typst(vec![Diagnostic::new(
+            Stage::Compile,
+            Severity::Error,
+            err.to_string(),
+        )])
+    })?;
+    let pdf_options = PdfOptions {
+        ident: options.ident.as_deref().map_or(Smart::Auto, Smart::Custom),
+        timestamp: datetime_from_unix(world.now).map(Timestamp::new_utc),
+        standards,
+        ..PdfOptions::default()
+    };
+    typst_pdf::pdf(document, &pdf_options).map_err(|diagnostics| {
+        let diagnostics = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let mut diagnostic = to_diagnostic(world, diagnostic);
+                if options.pdf_standard != PdfStandard::V1_7 {
+                    diagnostic.message = format!(
+                        "document does not conform to {}: {}",
+                        options.pdf_standard, diagnostic.message
+                    );
+                }
+                diagnostic
+            })
+            .collect();
+        CompileError::Typst(diagnostics)
+    })
+}
+
+/// The built-in styles list fonts for every platform, so most of their
+/// families are missing on any one machine. That is expected as long as
+/// another family of the same list is installed, so for an unknown family
+/// in the built-in style this returns the lists naming it, for the caller
+/// to check, instead of a warning per family.
+fn builtin_font_lists<'a>(
+    world: &MdxportWorld,
+    builtin: Option<&'a (usize, FontFallbacks)>,
+    warning: &SourceDiagnostic,
+) -> Option<Vec<(&'static str, &'a [String])>> {
+    let (builtin_len, fallbacks) = builtin?;
+    let family = warning.message.strip_prefix("unknown font family: ")?;
+    let in_builtin = warning.span.id() == Some(world.main_id)
+        && world
+            .main_source
+            .range(warning.span)
+            .is_some_and(|range| range.end <= *builtin_len);
+    if !in_builtin {
+        return None;
     }
+    Some(
+        [
+            ("title", &fallbacks.title),
+            ("body", &fallbacks.body),
+            ("code", &fallbacks.code),
+        ]
+        .into_iter()
+        .filter(|(_, families)| {
+            families
+                .iter()
+                .any(|listed| listed.eq_ignore_ascii_case(family))
+        })
+        .map(|(name, families)| (name, families.as_slice()))
+        .collect(),
+    )
 }
 
-struct FontStorage {
-    book: LazyHash<FontBook>,
-    fonts: Vec<FontSlot>,
+/// One warning for the font lists among `lists` that have no installed
+/// family at all, if any.
+fn unresolved_font_lists(book: &FontBook, lists: &[(&str, &[String])]) -> Option<Diagnostic> {
+    let mut unresolved: Vec<String> = Vec::new();
+    for (name, families) in lists {
+        let name = format!("`{name}-fonts`");
+        let resolved = families
+            .iter()
+            .any(|family| book.contains_family(&family.to_lowercase()));
+        if !resolved && !unresolved.contains(&name) {
+            unresolved.push(name);
+        }
+    }
+    if unresolved.is_empty() {
+        return None;
+    }
+    Some(Diagnostic {
+        hints: vec![
+            "install one of the listed fonts, pass --font-path, or run `mdxport fonts install`"
+                .to_string(),
+        ],
+        ..Diagnostic::new(
+            Stage::Compile,
+            Severity::Warning,
+            format!(
+                "no listed font is installed for the style's {}; Typst falls back to another font",
+                unresolved.join(", ")
+            ),
+        )
+    })
 }
 
-static FONT_STORAGE: OnceLock<FontStorage> = OnceLock::new();
+/// Locate `diagnostic` in the Typst source and, through the source map, in
+/// the Markdown it was generated from.
+fn to_diagnostic(world: &MdxportWorld, diagnostic: &SourceDiagnostic) -> Diagnostic {
+    let mut hints: Vec<String> = diagnostic.hints.iter().map(ToString::to_string).collect();
+    let (typst_line, typst_snippet, position) = match locate(world, diagnostic) {
+        // In a template or package file rather than the generated source
+        Some((id, line, snippet, _)) if id != world.main_id => {
+            hints.push(format!("in {} line {line}", world.describe_file(id)));
+            (None, snippet, None)
+        }
+        Some((_, line, snippet, position)) => (Some(line), snippet, position),
+        None => (None, None, None),
+    };
 
-impl FontStorage {
-    fn global() -> &'static Self {
-        FONT_STORAGE.get_or_init(|| {
-            let mut book = FontBook::new();
-            let mut fonts = Vec::new();
+    Diagnostic {
+        stage: Stage::Compile,
+        severity: match diagnostic.severity {
+            typst::diag::Severity::Error => Severity::Error,
+            typst::diag::Severity::Warning => Severity::Warning,
+        },
+        message: diagnostic.message.to_string(),
+        hints,
+        file: position.and(world.source_path.clone()),
+        position,
+        typst_line,
+        typst_snippet: typst_snippet.filter(|snippet| !snippet.is_empty()),
+    }
+}
 
-            // Bundled fonts from typst-assets (Libertinus Serif, New CM, DejaVu Sans Mono)
-            for data in typst_assets::fonts() {
-                let bytes = Bytes::new(data);
-                add_font_data(&mut book, &mut fonts, bytes);
-            }
+/// The file, 1-based line and snippet `diagnostic` points at, and for the
+/// main source its Markdown position.
+fn locate(
+    world: &MdxportWorld,
+    diagnostic: &SourceDiagnostic,
+) -> Option<(FileId, usize, Option<String>, Option<SourcePosition>)> {
+    let id = diagnostic.span.id()?;
+    let source = world.source(id).ok()?;
+    let range = source.range(diagnostic.span)?;
+    let line = source.byte_to_line(range.start)? + 1;
+    let snippet = source
+        .text()
+        .get(range.clone())
+        .and_then(|text| text.lines().next())
+        .map(|line| truncate(line.trim(), 60));
+    let position = world
+        .source_map
+        .as_ref()
+        .filter(|_| id == world.main_id)
+        .and_then(|map| map.lookup(range.start));
+    Some((id, line, snippet, position))
+}
 
-            // System fonts
-            for dir in system_font_dirs() {
-                scan_font_dir(&mut book, &mut fonts, &dir);
-            }
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((index, _)) => format!("{}…", &text[..index]),
+        None => text.to_string(),
+    }
+}
 
-            FontStorage {
-                book: LazyHash::new(book),
-                fonts,
-            }
-        })
+/// PDF identifier for the document converted from `input`: its file name,
+/// so `doc.md`, `./doc.md` and `/abs/doc.md` produce identical bytes.
+pub fn document_ident(input: &Path) -> String {
+    input
+        .file_name()
+        .unwrap_or(input.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// `SOURCE_DATE_EPOCH` if set (see reproducible-builds.org), otherwise the
+/// system clock, in seconds since the Unix epoch.
+pub fn current_timestamp() -> i64 {
+    if let Some(epoch) = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+    {
+        return epoch;
     }
+
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
 }
 
-fn add_font_data(book: &mut FontBook, fonts: &mut Vec<FontSlot>, data: Bytes) {
-    for index in 0_u32.. {
-        match FontInfo::new(data.as_slice(), index) {
-            Some(info) => {
-                book.push(info);
-                fonts.push(FontSlot::new(data.clone(), index));
-            }
-            None => break,
-        }
+/// Convert seconds since the Unix epoch to a UTC datetime.
+fn datetime_from_unix(secs: i64) -> Option<Datetime> {
+    let days = secs.div_euclid(86_400);
+    let secs_of_day = secs.rem_euclid(86_400);
+
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    Datetime::from_ymd_hms(
+        i32::try_from(year).ok()?,
+        month as u8,
+        day as u8,
+        (secs_of_day / 3_600) as u8,
+        (secs_of_day % 3_600 / 60) as u8,
+        (secs_of_day % 60) as u8,
+    )
+}
+
+fn write_output(output_path: &Path, bytes: &[u8]) -> Result<(), CompileError> {
+    if let Some(parent) = output_path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent).map_err(CompileError::Io)?;
     }
+    fs::write(output_path, bytes).map_err(CompileError::Io)
 }
 
-fn scan_font_dir(book: &mut FontBook, fonts: &mut Vec<FontSlot>, dir: &Path) {
-    let Ok(entries) = fs::read_dir(dir) else {
-        return;
-    };
+// ---------------------------------------------------------------------------
+// World implementation
+// ---------------------------------------------------------------------------
 
-    // Deduplicate by canonical path to avoid loading the same font twice
-    let mut seen = std::collections::HashSet::new();
+struct MdxportWorld {
+    library: LazyHash<Library>,
+    main_id: FileId,
+    main_source: Source,
+    files: HashMap<FileId, AssetSource>,
+    loaded: Mutex<HashMap<FileId, FileResult<Bytes>>>,
+    /// Typst files other than the main one, i.e. from packages.
+    sources: Mutex<HashMap<FileId, FileResult<Source>>>,
+    offline: bool,
+    #[cfg_attr(not(feature = "cli"), allow(dead_code))]
+    cache_dir: Option<PathBuf>,
+    #[cfg(feature = "cli")]
+    fetcher: OnceLock<Option<crate::fetch::AssetFetcher>>,
+    fonts: FontSet,
+    package_paths: Vec<PathBuf>,
+    packages: OnceLock<PackageStore>,
+    template_dir: Option<PathBuf>,
+    /// Fixed for the whole compilation so every `today()` call agrees.
+    now: i64,
+    source_map: Option<SourceMap>,
+    source_path: Option<PathBuf>,
+}
 
-    for entry in entries.flatten() {
-        let path = entry.path();
+impl MdxportWorld {
+    fn new(source: &str, options: &CompileOptions) -> Self {
+        let main_id = FileId::new(None, VirtualPath::new("/main.typ"));
+        let main_source = Source::new(main_id, source.to_string());
 
-        if path.is_dir() {
-            scan_font_dir(book, fonts, &path);
-            continue;
+        Self {
+            library: LazyHash::new(Library::default()),
+            main_id,
+            main_source,
+            files: asset_files(options),
+            loaded: Mutex::new(HashMap::new()),
+            sources: Mutex::new(HashMap::new()),
+            offline: options.offline,
+            cache_dir: options.cache_dir.clone(),
+            #[cfg(feature = "cli")]
+            fetcher: OnceLock::new(),
+            fonts: options.fonts.clone().unwrap_or_default(),
+            package_paths: options.package_paths.clone(),
+            packages: OnceLock::new(),
+            template_dir: options.template_dir.clone(),
+            now: options.timestamp.unwrap_or_else(current_timestamp),
+            source_map: options.source_map.clone(),
+            source_path: options.source_path.clone(),
         }
+    }
 
-        let ext = path
-            .extension()
-            .and_then(|e| e.to_str())
-            .map(str::to_lowercase);
+    /// Switch to a new version of the document. The main source is edited
+    /// in place rather than replaced so unchanged syntax keeps its spans.
+    fn update(&mut self, source: &str, options: &CompileOptions) {
+        if self.main_source.text() != source {
+            self.main_source.replace(source);
+        }
+        self.files = asset_files(options);
+        self.loaded
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.sources
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        #[cfg(feature = "cli")]
+        if self.offline != options.offline || self.cache_dir != options.cache_dir {
+            self.fetcher = OnceLock::new();
+        }
+        if self.offline != options.offline || self.package_paths != options.package_paths {
+            self.packages = OnceLock::new();
+        }
+        self.offline = options.offline;
+        self.package_paths.clone_from(&options.package_paths);
+        self.template_dir.clone_from(&options.template_dir);
+        self.cache_dir.clone_from(&options.cache_dir);
+        self.fonts = options.fonts.clone().unwrap_or_default();
+        self.now = options.timestamp.unwrap_or_else(current_timestamp);
+        self.source_map.clone_from(&options.source_map);
+        self.source_path.clone_from(&options.source_path);
+    }
 
-        match ext.as_deref() {
-            Some("ttf" | "otf" | "ttc" | "otc") => {}
-            _ => continue,
+    fn load(&self, source: &AssetSource) -> FileResult<Bytes> {
+        match source {
+            AssetSource::Local(path) => fs::read(path)
+                .map(Bytes::new)
+                .map_err(|err| FileError::from_io(err, path)),
+            AssetSource::Remote(url) => self.fetch_remote(url),
         }
+    }
 
-        if let Ok(canonical) = fs::canonicalize(&path)
-            && !seen.insert(canonical)
-        {
-            continue;
+    /// Where a file under the template mount lives on disk.
+    fn template_file(&self, id: FileId) -> Option<PathBuf> {
+        if id.package().is_some() {
+            return None;
         }
+        let dir = self.template_dir.as_ref()?;
+        let relative = id
+            .vpath()
+            .as_rootless_path()
+            .strip_prefix(TEMPLATE_MOUNT.trim_start_matches('/'))
+            .ok()?;
+        VirtualPath::new(relative).resolve(dir)
+    }
 
-        if let Ok(data) = fs::read(&path) {
-            add_font_data(book, fonts, Bytes::new(data));
+    /// A file that is not the main source, for messages: its path on disk
+    /// for template files, otherwise the package and path.
+    fn describe_file(&self, id: FileId) -> String {
+        match (self.template_file(id), id.package()) {
+            (Some(path), _) => path.display().to_string(),
+            (None, Some(spec)) => format!("{spec}/{}", id.vpath().as_rootless_path().display()),
+            (None, None) => id.vpath().as_rootless_path().display().to_string(),
         }
     }
-}
 
-fn system_font_dirs() -> Vec<PathBuf> {
-    let mut dirs = Vec::new();
+    /// Read a file from the package `id` belongs to.
+    fn load_package_file(&self, id: FileId) -> FileResult<Bytes> {
+        let spec = id.package().expect("package file id");
+        let root = self
+            .packages
+            .get_or_init(|| PackageStore::new(&self.package_paths, self.offline))
+            .resolve(spec)?;
+        let path = id.vpath().resolve(&root).ok_or(FileError::AccessDenied)?;
+        fs::read(&path)
+            .map(Bytes::new)
+            .map_err(|err| FileError::from_io(err, &path))
+    }
 
-    #[cfg(target_os = "macos")]
-    {
-        dirs.push(PathBuf::from("/System/Library/Fonts"));
-        dirs.push(PathBuf::from("/Library/Fonts"));
-        if let Some(home) = home_dir() {
-            dirs.push(home.join("Library/Fonts"));
+    #[cfg(feature = "cli")]
+    fn fetch_remote(&self, url: &str) -> FileResult<Bytes> {
+        use crate::fetch::{AssetFetcher, FetchError};
+
+        let fetcher = self.fetcher.get_or_init(|| {
+            let cache_dir = self
+                .cache_dir
+                .clone()
+                .or_else(AssetFetcher::default_cache_dir)?;
+            Some(AssetFetcher::new(cache_dir, self.offline))
+        });
+        let Some(fetcher) = fetcher else {
+            return Err(FileError::Other(Some(
+                "unable to determine cache directory".into(),
+            )));
+        };
+
+        match fetcher.fetch(url) {
+            Ok(data) => Ok(Bytes::new(data)),
+            Err(FetchError::NotCached) => Ok(Bytes::new(PLACEHOLDER_SVG.as_bytes())),
+            Err(err) => Err(FileError::Other(Some(format!("{url}: {err}").into()))),
         }
     }
 
-    #[cfg(target_os = "linux")]
-    {
-        dirs.push(PathBuf::from("/usr/share/fonts"));
-        dirs.push(PathBuf::from("/usr/local/share/fonts"));
-        if let Some(home) = home_dir() {
-            dirs.push(home.join(".local/share/fonts"));
-            dirs.push(home.join(".fonts"));
+    /// Without the HTTP client every remote image is a placeholder.
+    #[cfg(not(feature = "cli"))]
+    fn fetch_remote(&self, _url: &str) -> FileResult<Bytes> {
+        Ok(Bytes::new(PLACEHOLDER_SVG.as_bytes()))
+    }
+}
+
+fn asset_files(options: &CompileOptions) -> HashMap<FileId, AssetSource> {
+    options
+        .assets
+        .iter()
+        .map(|asset| {
+            let id = FileId::new(None, VirtualPath::new(&asset.virtual_path));
+            (id, asset.source.clone())
+        })
+        .collect()
+}
+
+impl World for MdxportWorld {
+    fn library(&self) -> &LazyHash<Library> {
+        &self.library
+    }
+
+    fn book(&self) -> &LazyHash<FontBook> {
+        self.fonts.book()
+    }
+
+    fn main(&self) -> FileId {
+        self.main_id
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        if id == self.main_id {
+            return Ok(self.main_source.clone());
         }
+        if let Some(source) = self
+            .sources
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&id)
+        {
+            return source.clone();
+        }
+
+        let source = self.file(id).and_then(|bytes| {
+            let text = std::str::from_utf8(&bytes).map_err(|_| FileError::InvalidUtf8)?;
+            let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+            Ok(Source::new(id, text.to_string()))
+        });
+        self.sources
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(id)
+            .or_insert(source)
+            .clone()
     }
 
-    #[cfg(target_os = "windows")]
-    {
-        if let Some(windir) = std::env::var_os("WINDIR") {
-            dirs.push(PathBuf::from(windir).join("Fonts"));
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        let source = self.files.get(&id);
+        let template_file = self.template_file(id);
+        if source.is_none() && id.package().is_none() && template_file.is_none() {
+            return Err(FileError::NotFound(id.vpath().as_rootless_path().into()));
         }
-        if let Some(local) = std::env::var_os("LOCALAPPDATA") {
-            dirs.push(PathBuf::from(local).join("Microsoft\\Fonts"));
+        if let Some(bytes) = self
+            .loaded
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&id)
+        {
+            return bytes.clone();
         }
+        // Loaded without holding the lock: fetching a remote image or a
+        // package must not block every other file access
+        let bytes = match (source, template_file) {
+            (Some(source), _) => self.load(source),
+            (None, Some(path)) => fs::read(&path)
+                .map(Bytes::new)
+                .map_err(|err| FileError::from_io(err, &path)),
+            (None, None) => self.load_package_file(id),
+        };
+        // Another thread may have loaded it meanwhile; keep the first result
+        // so every caller sees the same bytes
+        self.loaded
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(id)
+            .or_insert(bytes)
+            .clone()
     }
 
-    if let Some(home) = home_dir() {
-        dirs.push(home.join(".mdxport").join("fonts"));
+    fn font(&self, index: usize) -> Option<Font> {
+        self.fonts.font(index)
     }
 
-    dirs
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        // Without an explicit offset, "local" time is UTC: output must not
+        // depend on the machine's timezone
+        let hours = offset.unwrap_or(0);
+        let now = datetime_from_unix(self.now.checked_add(hours.checked_mul(3_600)?)?)?;
+        Datetime::from_ymd(now.year()?, now.month()?, now.day()?)
+    }
 }
 
-fn home_dir() -> Option<PathBuf> {
-    std::env::var_os("HOME")
-        .or_else(|| std::env::var_os("USERPROFILE"))
-        .map(PathBuf::from)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_seconds_to_datetime() {
+        assert_eq!(
+            datetime_from_unix(0),
+            Datetime::from_ymd_hms(1970, 1, 1, 0, 0, 0)
+        );
+        assert_eq!(
+            datetime_from_unix(1_709_210_096),
+            Datetime::from_ymd_hms(2024, 2, 29, 12, 34, 56)
+        );
+    }
+
+    #[test]
+    fn builtin_font_lists_warn_only_when_no_family_resolves() {
+        let fallbacks = FontFallbacks {
+            title: vec!["Missing Sans".into(), "Libertinus Serif".into()],
+            body: vec!["Missing Sans".into(), "Libertinus Serif".into()],
+            code: vec!["Missing Mono".into(), "Other Mono".into()],
+        };
+        let lists = [
+            ("title", fallbacks.title.as_slice()),
+            ("body", fallbacks.body.as_slice()),
+            ("code", fallbacks.code.as_slice()),
+            ("code", fallbacks.code.as_slice()),
+        ];
+        let fonts = FontSet::new(&crate::fonts::FontOptions {
+            font_paths: Vec::new(),
+            system_fonts: false,
+        });
+
+        assert!(unresolved_font_lists(fonts.book(), &lists[..2]).is_none());
+        let warning = unresolved_font_lists(fonts.book(), &lists).unwrap();
+        assert_eq!(warning.severity, Severity::Warning);
+        assert_eq!(
+            warning.message,
+            "no listed font is installed for the style's `code-fonts`; Typst falls back to another font"
+        );
+
+        let warning = unresolved_font_lists(&FontBook::new(), &lists).unwrap();
+        assert!(
+            warning
+                .message
+                .contains("`title-fonts`, `body-fonts`, `code-fonts`;")
+        );
+    }
 }
@@ -1,16 +1,27 @@
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
+use std::path::{Component, Path, PathBuf};
 
 use comrak::{
     Arena, ComrakOptions,
-    nodes::{AstNode, ListType, NodeCodeBlock, NodeList, NodeMath, NodeValue, TableAlignment},
+    nodes::{
+        AstNode, ListType, NodeCodeBlock, NodeLink, NodeList, NodeMath, NodeValue, TableAlignment,
+    },
     parse_document,
 };
 
-use crate::frontmatter::FrontMatter;
+use crate::diagnostic::{Diagnostic, Severity, SourceMap, SourcePosition, Stage};
+use crate::frontmatter::{DocumentDate, FrontMatter};
 use crate::math::latex_to_typst;
 
 const TOC_TOKEN: &str = "MDXPORTTOCPLACEHOLDER7f3a";
+const TABLE_CAPTION_PREFIX: &str = "Table:";
+const LISTING_CAPTION_PREFIX: &str = "Listing:";
+/// Rendered nodes are prefixed with `\u{1}line:column\u{2}`; the markers are
+/// stripped into a [`SourceMap`] once rendering is done.
+const SOURCE_MARK_START: char = '\u{1}';
+const SOURCE_MARK_END: char = '\u{2}';
 
 #[derive(Debug)]
 pub struct ConvertError {
@@ -25,12 +36,24 @@ impl Display for ConvertError {
 
 impl std::error::Error for ConvertError {}
 
+impl From<&ConvertError> for Diagnostic {
+    fn from(error: &ConvertError) -> Self {
+        Diagnostic::new(Stage::Convert, Severity::Error, error.message.clone())
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ConvertOptions {
     pub title_override: Option<String>,
     pub author_override: Option<String>,
     pub lang_override: Option<String>,
     pub force_toc: Option<bool>,
+    /// Directory relative image paths are resolved against. `None` uses the
+    /// current working directory.
+    pub base_dir: Option<PathBuf>,
+    /// Number of lines preceding the Markdown body in its source file (e.g. a
+    /// stripped frontmatter block), added to reported line numbers.
+    pub line_offset: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -40,6 +63,66 @@ pub struct ConvertedDocument {
     pub lang: String,
     pub body: String,
     pub toc: bool,
+    /// Local files referenced by `body` that must be served to Typst.
+    pub assets: Vec<Asset>,
+    pub warnings: Vec<ConvertWarning>,
+    pub metadata: DocumentMetadata,
+    /// Maps byte offsets in `body` to the Markdown they were rendered from.
+    pub source_map: SourceMap,
+}
+
+/// PDF document properties besides title and authors.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DocumentMetadata {
+    pub keywords: Vec<String>,
+    pub subject: Option<String>,
+    pub date: Option<DocumentDate>,
+    /// The whole frontmatter, passed to `article()` as a `meta` dictionary.
+    pub meta: serde_yaml::Mapping,
+}
+
+/// A file referenced from the generated Typst source, served under
+/// `virtual_path` (an absolute path inside the Typst project root).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asset {
+    pub virtual_path: String,
+    pub source: AssetSource,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssetSource {
+    /// A file on the local filesystem.
+    Local(PathBuf),
+    /// An `http(s)://` URL, fetched when the document is compiled.
+    Remote(String),
+}
+
+/// A non-fatal problem found while converting, e.g. a missing image.
+#[derive(Debug, Clone)]
+pub struct ConvertWarning {
+    /// 1-based line in the Markdown source.
+    pub line: usize,
+    /// 1-based column in the Markdown source.
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for ConvertWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl From<&ConvertWarning> for Diagnostic {
+    fn from(warning: &ConvertWarning) -> Self {
+        Self {
+            position: Some(SourcePosition {
+                line: warning.line,
+                column: warning.column,
+            }),
+            ..Diagnostic::new(Stage::Convert, Severity::Warning, warning.message.clone())
+        }
+    }
 }
 
 pub fn convert_markdown_to_typst(
@@ -49,26 +132,15 @@ pub fn convert_markdown_to_typst(
 ) -> Result<ConvertedDocument, ConvertError> {
     let (normalized, has_inline_toc) = normalize_toc_tokens(markdown);
 
-    let mut comrak_options = ComrakOptions::default();
-    comrak_options.extension.table = true;
-    comrak_options.extension.strikethrough = true;
-    comrak_options.extension.tasklist = true;
-    comrak_options.extension.footnotes = true;
-    comrak_options.extension.superscript = true;
-    comrak_options.extension.autolink = true;
-    comrak_options.extension.math_dollars = true;
-    comrak_options.extension.math_code = true;
-    comrak_options.extension.subscript = true;
-    comrak_options.extension.underline = true;
-
     let arena = Arena::new();
-    let root = parse_document(&arena, &normalized, &comrak_options);
+    let root = parse_document(&arena, &normalized, &comrak_options());
 
     let toc_enabled = options
         .force_toc
         .unwrap_or_else(|| frontmatter.toc.unwrap_or(has_inline_toc));
 
-    let mut renderer = TypstRenderer::new(toc_enabled);
+    let mut renderer = TypstRenderer::new(toc_enabled, options);
+    renderer.collect_labels(root);
     renderer.collect_footnotes(root);
 
     let body = renderer.render_blocks(root, 0).trim().to_string();
@@ -77,6 +149,7 @@ pub fn convert_markdown_to_typst(
     } else {
         format!("{body}\n")
     };
+    let (body, source_map) = strip_source_marks(&body);
 
     let lang = options
         .lang_override
@@ -109,9 +182,117 @@ pub fn convert_markdown_to_typst(
         lang,
         body,
         toc: toc_enabled && !has_inline_toc,
+        assets: renderer.assets.into_inner(),
+        warnings: renderer.warnings.into_inner(),
+        source_map,
+        metadata: DocumentMetadata {
+            keywords: frontmatter
+                .keywords
+                .iter()
+                .filter_map(|keyword| non_empty_str(keyword))
+                .map(ToOwned::to_owned)
+                .collect(),
+            subject: frontmatter
+                .subject
+                .as_deref()
+                .and_then(non_empty_str)
+                .map(ToOwned::to_owned),
+            date: frontmatter.date.as_deref().and_then(DocumentDate::parse),
+            meta: frontmatter.fields.clone(),
+        },
     })
 }
 
+/// Where a piece of document text is set, which decides the template font
+/// list it is drawn from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextRole {
+    Heading,
+    Body,
+    Code,
+}
+
+/// A run of visible text and the Markdown position it starts at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextRun {
+    pub text: String,
+    pub role: TextRole,
+    pub position: SourcePosition,
+}
+
+/// The visible text of a Markdown body, e.g. for checking font coverage.
+/// Math is left out since it is set in the math font. Each run is a single
+/// line; `line_offset` is as in [`ConvertOptions`].
+pub fn document_text(markdown: &str, line_offset: usize) -> Vec<TextRun> {
+    let (normalized, _) = normalize_toc_tokens(markdown);
+    let arena = Arena::new();
+    let root = parse_document(&arena, &normalized, &comrak_options());
+    let lines = normalized.lines().collect::<Vec<_>>();
+
+    let mut runs = Vec::new();
+    let mut push = |text: &str, role, line: usize, column: usize| {
+        if text.trim().is_empty() || text == TOC_TOKEN {
+            return;
+        }
+        // Escapes and entities make the text differ from the source; then
+        // the run at least points at the start of its node
+        let column = lines
+            .get(line.wrapping_sub(1))
+            .and_then(|source| source.get(column.saturating_sub(1)..))
+            .and_then(|rest| rest.find(text))
+            .map_or(column, |skip| column + skip);
+        runs.push(TextRun {
+            text: text.to_string(),
+            role,
+            position: SourcePosition {
+                line: line + line_offset,
+                column,
+            },
+        });
+    };
+
+    for node in root.descendants() {
+        let start = node.data.borrow().sourcepos.start;
+        match &node.data.borrow().value {
+            NodeValue::Text(text) => {
+                let in_heading = node
+                    .ancestors()
+                    .any(|ancestor| matches!(ancestor.data.borrow().value, NodeValue::Heading(_)));
+                let role = if in_heading {
+                    TextRole::Heading
+                } else {
+                    TextRole::Body
+                };
+                push(text, role, start.line, start.column);
+            }
+            NodeValue::Code(code) => push(&code.literal, TextRole::Code, start.line, start.column),
+            NodeValue::CodeBlock(code) => {
+                let first = start.line + usize::from(code.fenced);
+                for (index, line) in code.literal.lines().enumerate() {
+                    push(line, TextRole::Code, first + index, 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    runs
+}
+
+fn comrak_options() -> ComrakOptions<'static> {
+    let mut comrak_options = ComrakOptions::default();
+    comrak_options.extension.table = true;
+    comrak_options.extension.strikethrough = true;
+    comrak_options.extension.tasklist = true;
+    comrak_options.extension.footnotes = true;
+    comrak_options.extension.superscript = true;
+    comrak_options.extension.autolink = true;
+    comrak_options.extension.math_dollars = true;
+    comrak_options.extension.math_code = true;
+    comrak_options.extension.subscript = true;
+    comrak_options.extension.underline = true;
+    comrak_options
+}
+
 fn resolve_authors(frontmatter: &FrontMatter, options: &ConvertOptions) -> Vec<String> {
     if let Some(author) = options
         .author_override
@@ -176,13 +357,72 @@ fn normalize_toc_tokens(markdown: &str) -> (String, bool) {
 struct TypstRenderer {
     toc_enabled: bool,
     footnotes: HashMap<String, String>,
+    /// Labels attached to a figure, table or listing; `@label` references
+    /// to anything else are rendered as plain text.
+    labels: HashSet<String>,
+    base_dir: Option<PathBuf>,
+    line_offset: usize,
+    assets: RefCell<Vec<Asset>>,
+    warnings: RefCell<Vec<ConvertWarning>>,
 }
 
 impl TypstRenderer {
-    fn new(toc_enabled: bool) -> Self {
+    fn new(toc_enabled: bool, options: &ConvertOptions) -> Self {
         Self {
             toc_enabled,
             footnotes: HashMap::new(),
+            labels: HashSet::new(),
+            base_dir: options.base_dir.clone(),
+            line_offset: options.line_offset,
+            assets: RefCell::new(Vec::new()),
+            warnings: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn warn<'a>(&self, node: &'a AstNode<'a>, message: String) {
+        let start = node.data.borrow().sourcepos.start;
+        self.warnings.borrow_mut().push(ConvertWarning {
+            line: start.line + self.line_offset,
+            column: start.column,
+            message,
+        });
+    }
+
+    /// Prefix `rendered` with a source marker for `node`, after any leading
+    /// whitespace so that trimming behaves as if the marker were absent.
+    fn mark<'a>(&self, node: &'a AstNode<'a>, rendered: String) -> String {
+        let start = node.data.borrow().sourcepos.start;
+        let content = rendered.trim_start();
+        if content.is_empty() || start.line == 0 {
+            return rendered;
+        }
+
+        let whitespace = &rendered[..rendered.len() - content.len()];
+        format!(
+            "{whitespace}{SOURCE_MARK_START}{}:{}{SOURCE_MARK_END}{content}",
+            start.line + self.line_offset,
+            start.column
+        )
+    }
+
+    fn collect_labels<'a>(&mut self, root: &'a AstNode<'a>) {
+        for node in root.descendants() {
+            let label = match &node.data.borrow().value {
+                // Only a paragraph's sole image becomes a figure
+                NodeValue::Paragraph => {
+                    standalone_image(node).and_then(|(image, _)| image_label(image))
+                }
+                NodeValue::Table(_) => {
+                    caption_paragraph(node, TABLE_CAPTION_PREFIX).and_then(caption_label)
+                }
+                NodeValue::CodeBlock(code) => code_label(&code.info).or_else(|| {
+                    caption_paragraph(node, LISTING_CAPTION_PREFIX).and_then(caption_label)
+                }),
+                _ => None,
+            };
+            if let Some(label) = label {
+                self.labels.insert(label);
+            }
         }
     }
 
@@ -216,7 +456,7 @@ impl TypstRenderer {
 
     fn render_block<'a>(&self, node: &'a AstNode<'a>, indent: usize) -> String {
         let value = node.data.borrow().value.clone();
-        match value {
+        let rendered = match value {
             NodeValue::Document => self.render_blocks(node, indent),
             NodeValue::FrontMatter(_) => String::new(),
             NodeValue::Paragraph => self.render_paragraph(node),
@@ -239,7 +479,7 @@ impl TypstRenderer {
             }
             NodeValue::List(list) => self.render_list(node, &list, indent),
             NodeValue::Item(_) | NodeValue::TaskItem(_) => self.render_blocks(node, indent),
-            NodeValue::CodeBlock(code) => render_code_block(&code),
+            NodeValue::CodeBlock(code) => self.render_code_block(node, &code),
             NodeValue::ThematicBreak => "#line(length: 100%, stroke: 0.5pt)\n\n".to_string(),
             NodeValue::Table(table) => self.render_table(node, &table),
             NodeValue::TableRow(_) | NodeValue::TableCell => self.render_blocks(node, indent),
@@ -263,14 +503,23 @@ impl TypstRenderer {
             NodeValue::HtmlBlock(_) => String::new(),
             other if other.block() => self.render_blocks(node, indent),
             _ => String::new(),
-        }
+        };
+        self.mark(node, rendered)
     }
 
     fn render_paragraph<'a>(&self, node: &'a AstNode<'a>) -> String {
+        if is_caption_paragraph(node) {
+            return String::new();
+        }
+
         if let Some(block_math) = self.extract_single_display_math(node) {
             return format!("{block_math}\n\n");
         }
 
+        if let Some((image, link)) = standalone_image(node) {
+            return format!("{}\n\n", self.render_image_figure(image, &link));
+        }
+
         let text = self.render_inlines(node).trim().to_string();
         if text.is_empty() {
             String::new()
@@ -371,7 +620,8 @@ impl TypstRenderer {
                 continue;
             }
 
-            out.push_str(&self.render_list_item(item, ordered, index, indent));
+            let rendered = self.render_list_item(item, ordered, index, indent);
+            out.push_str(&self.mark(item, rendered));
             if ordered {
                 index += 1;
             }
@@ -468,7 +718,7 @@ impl TypstRenderer {
         }
 
         let mut out = String::new();
-        out.push_str("#table(\n");
+        out.push_str("table(\n");
         out.push_str(&format!("  columns: {max_cols},\n"));
 
         let alignments = table
@@ -491,8 +741,57 @@ impl TypstRenderer {
                 out.push_str("],\n");
             }
         }
+        out.push(')');
+
+        match caption_paragraph(table_node, TABLE_CAPTION_PREFIX) {
+            Some(caption) => {
+                let figure = self.render_figure(
+                    &out,
+                    &self.render_caption(caption, TABLE_CAPTION_PREFIX),
+                    caption_label(caption),
+                );
+                format!("{figure}\n\n")
+            }
+            None => format!("#{out}\n\n"),
+        }
+    }
+
+    fn render_code_block<'a>(&self, node: &'a AstNode<'a>, code: &NodeCodeBlock) -> String {
+        let raw = render_raw_block(code);
+        let caption = caption_paragraph(node, LISTING_CAPTION_PREFIX);
+        let label = code_label(&code.info).or_else(|| caption.and_then(caption_label));
+
+        if caption.is_none() && label.is_none() {
+            return format!("{raw}\n\n");
+        }
+
+        let caption = caption
+            .map(|caption| self.render_caption(caption, LISTING_CAPTION_PREFIX))
+            .unwrap_or_default();
+        format!("{}\n\n", self.render_figure(&raw, &caption, label))
+    }
 
-        out.push_str(")\n\n");
+    /// Render a caption paragraph without its `Table:`-style prefix. The
+    /// trailing `{#label}` is dropped by `render_text`.
+    fn render_caption<'a>(&self, paragraph: &'a AstNode<'a>, prefix: &str) -> String {
+        let text = self.render_inlines(paragraph);
+        let text = text.trim();
+        text.strip_prefix(prefix).unwrap_or(text).trim().to_string()
+    }
+
+    /// Wrap a Typst expression (without leading `#`) in a numbered figure.
+    fn render_figure(&self, body: &str, caption: &str, label: Option<String>) -> String {
+        let mut out = String::new();
+        out.push_str("#figure(\n");
+        out.push_str(&indent_block(body, 1));
+        out.push_str(",\n");
+        if !caption.is_empty() {
+            out.push_str(&format!("  caption: [{caption}],\n"));
+        }
+        out.push(')');
+        if let Some(label) = label {
+            out.push_str(&format!(" <{label}>"));
+        }
         out
     }
 
@@ -543,15 +842,29 @@ impl TypstRenderer {
 
     fn render_inlines<'a>(&self, parent: &'a AstNode<'a>) -> String {
         let mut out = String::new();
+        // Adjacent text nodes are merged so references split by comrak
+        // (e.g. `[`, `@fig:x`, `]`) are seen as a whole.
+        let mut text = String::new();
         for node in parent.children() {
+            if let NodeValue::Text(literal) = &node.data.borrow().value {
+                text.push_str(literal);
+                continue;
+            }
+            if !text.is_empty() {
+                out.push_str(&self.render_text(&text));
+                text.clear();
+            }
             out.push_str(&self.render_inline(node));
         }
+        if !text.is_empty() {
+            out.push_str(&self.render_text(&text));
+        }
         out
     }
 
     fn render_inline<'a>(&self, node: &'a AstNode<'a>) -> String {
         let value = node.data.borrow().value.clone();
-        match value {
+        let rendered = match value {
             NodeValue::Text(text) => self.render_text(&text),
             NodeValue::Code(code) => render_inline_code(&code.literal),
             NodeValue::SoftBreak => " ".to_string(),
@@ -572,15 +885,9 @@ impl TypstRenderer {
                 };
                 format!("#link(\"{}\")[{}]", escape_string(&link.url), label)
             }
-            NodeValue::Image(link) => {
-                let alt = self.render_inlines(node).trim().to_string();
-                let label = if alt.is_empty() {
-                    "image".to_string()
-                } else {
-                    alt
-                };
-                format!("#link(\"{}\")[{}]", escape_string(&link.url), label)
-            }
+            // Figures are block-level: an image within text stays inline,
+            // see `render_paragraph` for images standing alone
+            NodeValue::Image(link) => format!("#{}", self.render_image(node, &link.url)),
             NodeValue::WikiLink(link) => {
                 let label = if link.url.trim().is_empty() {
                     "wiki".to_string()
@@ -603,24 +910,382 @@ impl TypstRenderer {
             NodeValue::HtmlInline(_) => String::new(),
             other if !other.block() => self.render_inlines(node),
             _ => String::new(),
+        };
+        self.mark(node, rendered)
+    }
+
+    /// Render an image as a Typst expression (without leading `#`). Images
+    /// that cannot be embedded fall back to a link.
+    fn render_image<'a>(&self, node: &'a AstNode<'a>, url: &str) -> String {
+        let Some(virtual_path) = self.register_image(node, url) else {
+            let alt = self.render_inlines(node).trim().to_string();
+            let label = if alt.is_empty() {
+                "image".to_string()
+            } else {
+                alt
+            };
+            return format!("link(\"{}\")[{}]", escape_string(url), label);
+        };
+
+        let alt = plain_text(node);
+        let alt = alt.trim();
+        if alt.is_empty() {
+            format!("image(\"{}\")", escape_string(&virtual_path))
+        } else {
+            format!(
+                "image(\"{}\", alt: \"{}\")",
+                escape_string(&virtual_path),
+                escape_string(alt)
+            )
         }
     }
 
+    /// Render an image as a figure captioned by its title, or by its alt
+    /// text when it has no title.
+    fn render_image_figure<'a>(&self, node: &'a AstNode<'a>, link: &NodeLink) -> String {
+        let caption = if link.title.trim().is_empty() {
+            self.render_inlines(node).trim().to_string()
+        } else {
+            escape_text(link.title.trim())
+        };
+        let image = self.render_image(node, &link.url);
+        self.render_figure(&image, &caption, image_label(node))
+    }
+
+    /// Resolve an image and record it as an asset, returning the virtual path
+    /// to reference it by. Missing and unsupported (e.g. `data:`) images
+    /// yield `None`.
+    fn register_image<'a>(&self, node: &'a AstNode<'a>, url: &str) -> Option<String> {
+        let url = url.trim();
+        let source = if is_http_url(url) {
+            AssetSource::Remote(url.to_string())
+        } else if url.is_empty() || url.contains("://") || url.starts_with("data:") {
+            return None;
+        } else if let Some(path) = self.resolve_local_path(url) {
+            AssetSource::Local(path)
+        } else {
+            self.warn(node, format!("image not found: {url}"));
+            return None;
+        };
+
+        let mut assets = self.assets.borrow_mut();
+        if let Some(existing) = assets.iter().find(|asset| asset.source == source) {
+            return Some(existing.virtual_path.clone());
+        }
+
+        let virtual_path = asset_virtual_path(url, &source, &assets);
+        assets.push(Asset {
+            virtual_path: virtual_path.clone(),
+            source,
+        });
+        Some(virtual_path)
+    }
+
+    fn resolve_local_path(&self, url: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(url);
+        [url, decoded.as_str()].into_iter().find_map(|candidate| {
+            let path = match &self.base_dir {
+                Some(base) => base.join(candidate),
+                None => PathBuf::from(candidate),
+            };
+            path.is_file().then_some(path)
+        })
+    }
+
     fn render_text(&self, text: &str) -> String {
         if !text.contains(TOC_TOKEN) {
-            return escape_text(text);
+            return self.render_references(text);
         }
 
         let mut out = String::new();
         let mut pieces = text.split(TOC_TOKEN).peekable();
         while let Some(piece) = pieces.next() {
-            out.push_str(&escape_text(piece));
+            out.push_str(&self.render_references(piece));
             if pieces.peek().is_some() && self.toc_enabled {
                 out.push_str("\n#outline()\n");
             }
         }
         out
     }
+
+    /// Escape plain text, turning `@label` and `[@label]` into Typst
+    /// references and dropping `{#label}` attributes of known labels.
+    fn render_references(&self, text: &str) -> String {
+        let mut out = String::new();
+        let mut plain_start = 0;
+        let mut i = 0;
+
+        while i < text.len() {
+            let rest = &text[i..];
+
+            if let Some(label) = leading_label_attr(rest)
+                && self.labels.contains(label)
+            {
+                out.push_str(&escape_text(&text[plain_start..i]));
+                i += label.len() + 3;
+                plain_start = i;
+                continue;
+            }
+
+            let reference = if let Some(candidate) = rest.strip_prefix('@') {
+                let label = reference_label(candidate);
+                Some((label, label.len() + 1))
+            } else if let Some(candidate) = rest.strip_prefix("[@") {
+                let label = reference_label(candidate);
+                candidate[label.len()..]
+                    .starts_with(']')
+                    .then_some((label, label.len() + 3))
+            } else {
+                None
+            };
+
+            if let Some((label, consumed)) = reference
+                && self.labels.contains(label)
+            {
+                out.push_str(&escape_text(&text[plain_start..i]));
+                out.push('@');
+                out.push_str(label);
+                i += consumed;
+                plain_start = i;
+                continue;
+            }
+
+            i += rest.chars().next().map_or(1, char::len_utf8);
+        }
+
+        out.push_str(&escape_text(&text[plain_start..]));
+        out
+    }
+}
+
+/// Remove the markers left by `TypstRenderer::mark`, recording where each
+/// one was in the returned text.
+fn strip_source_marks(marked: &str) -> (String, SourceMap) {
+    let mut out = String::with_capacity(marked.len());
+    let mut source_map = SourceMap::default();
+    let mut rest = marked;
+
+    while let Some(start) = rest.find(SOURCE_MARK_START) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + SOURCE_MARK_START.len_utf8()..];
+        let position = after.split_once(SOURCE_MARK_END).and_then(|(mark, tail)| {
+            let (line, column) = mark.split_once(':')?;
+            let position = SourcePosition {
+                line: line.parse().ok()?,
+                column: column.parse().ok()?,
+            };
+            Some((position, tail))
+        });
+
+        match position {
+            Some((position, tail)) => {
+                source_map.push(out.len(), position);
+                rest = tail;
+            }
+            // A stray control character from the input, not one of ours
+            None => {
+                out.push(SOURCE_MARK_START);
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+
+    (out, source_map)
+}
+
+/// Return the image of a paragraph that consists of a single image,
+/// optionally followed by a `{#label}` attribute.
+fn standalone_image<'a>(paragraph: &'a AstNode<'a>) -> Option<(&'a AstNode<'a>, NodeLink)> {
+    let mut image = None;
+    let mut trailing = String::new();
+
+    for child in paragraph.children() {
+        match &child.data.borrow().value {
+            NodeValue::Image(link) if image.is_none() => image = Some((child, link.clone())),
+            NodeValue::Text(text) if image.is_some() => trailing.push_str(text),
+            NodeValue::SoftBreak if image.is_some() => trailing.push(' '),
+            _ => return None,
+        }
+    }
+
+    let trailing = trailing.trim();
+    if trailing.is_empty()
+        || leading_label_attr(trailing).is_some_and(|label| trailing.len() == label.len() + 3)
+    {
+        image
+    } else {
+        None
+    }
+}
+
+/// The `{#label}` attribute directly following an image, if any.
+fn image_label<'a>(image: &'a AstNode<'a>) -> Option<String> {
+    let next = image.next_sibling()?;
+    let NodeValue::Text(text) = &next.data.borrow().value else {
+        return None;
+    };
+    leading_label_attr(text.trim_start()).map(ToOwned::to_owned)
+}
+
+/// The paragraph following `node` if it starts with the caption `prefix`
+/// (`Table: ...` or `Listing: ...`).
+fn caption_paragraph<'a>(node: &'a AstNode<'a>, prefix: &str) -> Option<&'a AstNode<'a>> {
+    let next = node.next_sibling()?;
+    if !matches!(next.data.borrow().value, NodeValue::Paragraph) {
+        return None;
+    }
+    plain_text(next)
+        .trim_start()
+        .starts_with(prefix)
+        .then_some(next)
+}
+
+fn is_caption_paragraph<'a>(paragraph: &'a AstNode<'a>) -> bool {
+    let Some(previous) = paragraph.previous_sibling() else {
+        return false;
+    };
+    let prefix = match previous.data.borrow().value {
+        NodeValue::Table(_) => TABLE_CAPTION_PREFIX,
+        NodeValue::CodeBlock(_) => LISTING_CAPTION_PREFIX,
+        _ => return false,
+    };
+    caption_paragraph(previous, prefix).is_some()
+}
+
+/// The `{#label}` attribute at the end of a caption paragraph.
+fn caption_label<'a>(paragraph: &'a AstNode<'a>) -> Option<String> {
+    let text = plain_text(paragraph);
+    let text = text.trim_end();
+    let start = text.rfind("{#")?;
+    leading_label_attr(&text[start..])
+        .filter(|label| start + label.len() + 3 == text.len())
+        .map(ToOwned::to_owned)
+}
+
+/// A `{#label}` attribute in a fenced code block's info string.
+fn code_label(info: &str) -> Option<String> {
+    info.split_whitespace()
+        .find_map(leading_label_attr)
+        .map(ToOwned::to_owned)
+}
+
+/// Parse a `{#label}` attribute at the start of `text`, returning the label.
+fn leading_label_attr(text: &str) -> Option<&str> {
+    let inner = text.strip_prefix("{#")?;
+    let end = inner.find('}')?;
+    let label = &inner[..end];
+    let valid =
+        !label.is_empty() && label.chars().all(is_label_char) && !label.ends_with(['.', ':']);
+    valid.then_some(label)
+}
+
+/// The label of a reference following `@`, using Typst's rule that a
+/// reference cannot end in `.` or `:`.
+fn reference_label(text: &str) -> &str {
+    let end = text
+        .char_indices()
+        .find(|(_, ch)| !is_label_char(*ch))
+        .map_or(text.len(), |(idx, _)| idx);
+    text[..end].trim_end_matches(['.', ':'])
+}
+
+fn is_label_char(ch: char) -> bool {
+    ch.is_alphanumeric() || matches!(ch, '_' | '-' | ':' | '.')
+}
+
+fn plain_text<'a>(node: &'a AstNode<'a>) -> String {
+    let mut out = String::new();
+    for child in node.children() {
+        match &child.data.borrow().value {
+            NodeValue::Text(text) => out.push_str(text),
+            NodeValue::Code(code) => out.push_str(&code.literal),
+            NodeValue::Math(math) => out.push_str(&math.literal),
+            NodeValue::SoftBreak | NodeValue::LineBreak => out.push(' '),
+            _ => out.push_str(&plain_text(child)),
+        }
+    }
+    out
+}
+
+fn is_http_url(url: &str) -> bool {
+    let lower = url.to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(hex) = input.get(i + 1..i + 3)
+            && let Ok(byte) = u8::from_str_radix(hex, 16)
+        {
+            out.push(byte);
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(out).unwrap_or_else(|_| input.to_string())
+}
+
+/// Pick the path an asset is served under. Plain relative paths keep their
+/// layout so the generated source stays readable; anything reaching outside
+/// the document directory is flattened into `/assets/`, and remote images
+/// go to `/remote/` without an extension so Typst detects their format from
+/// the downloaded data rather than trusting the URL.
+fn asset_virtual_path(url: &str, source: &AssetSource, taken: &[Asset]) -> String {
+    let preferred = match source {
+        AssetSource::Local(path) => local_virtual_path(url, path),
+        AssetSource::Remote(url) => format!("/remote/{}", remote_file_stem(url)),
+    };
+
+    let is_taken = |candidate: &str| taken.iter().any(|asset| asset.virtual_path == candidate);
+    if !is_taken(&preferred) {
+        return preferred;
+    }
+
+    let (dir, file_name) = preferred.rsplit_once('/').unwrap_or(("", &preferred));
+    (1..)
+        .map(|n| format!("{dir}/{n}-{file_name}"))
+        .find(|candidate| !is_taken(candidate))
+        .unwrap_or(preferred)
+}
+
+fn local_virtual_path(url: &str, source: &Path) -> String {
+    let mut segments = Vec::new();
+    let mut is_plain_relative = true;
+    for component in Path::new(url).components() {
+        match component {
+            Component::Normal(segment) => segments.push(segment.to_string_lossy()),
+            Component::CurDir => {}
+            _ => is_plain_relative = false,
+        }
+    }
+
+    if is_plain_relative && !segments.is_empty() {
+        format!("/{}", segments.join("/"))
+    } else {
+        let file_name = source
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "image".to_string());
+        format!("/assets/{file_name}")
+    }
+}
+
+/// The last path segment of a URL without extension, query or fragment.
+fn remote_file_stem(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let path = path.split_once("://").map_or(path, |(_, rest)| rest);
+    path.split_once('/')
+        .and_then(|(_, path)| path.rsplit('/').find(|segment| !segment.is_empty()))
+        .map(|segment| percent_decode(segment.split('.').next().unwrap_or(segment)))
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| "image".to_string())
 }
 
 fn table_alignment(alignment: &TableAlignment) -> &'static str {
@@ -677,8 +1342,13 @@ fn wrap_markup(marker: &str, body: &str) -> String {
     }
 }
 
-fn render_code_block(code: &NodeCodeBlock) -> String {
-    let language = code.info.split_whitespace().next().unwrap_or("");
+fn render_raw_block(code: &NodeCodeBlock) -> String {
+    let language = code
+        .info
+        .split_whitespace()
+        .next()
+        .filter(|token| !token.starts_with('{'))
+        .unwrap_or("");
     let fence = backtick_fence(&code.literal, 3);
 
     let mut out = String::new();
@@ -690,7 +1360,6 @@ fn render_code_block(code: &NodeCodeBlock) -> String {
     out.push_str(code.literal.trim_end_matches('\n'));
     out.push('\n');
     out.push_str(&fence);
-    out.push_str("\n\n");
     out
 }
 
@@ -738,6 +1407,7 @@ fn escape_text(input: &str) -> String {
             '_' => out.push_str("\\_"),
             '$' => out.push_str("\\$"),
             '`' => out.push_str("\\`"),
+            '@' => out.push_str("\\@"),
             _ => out.push(ch),
         }
     }
@@ -829,9 +1499,163 @@ mod tests {
 
     #[test]
     fn image() {
-        // Currently images render as links (Typst can't load arbitrary paths)
+        // Missing images fall back to a link and produce a warning
         let doc = convert("![alt text](image.png)");
         assert!(doc.body.contains("image.png"));
+        assert!(doc.assets.is_empty());
+    }
+
+    #[test]
+    fn local_image_is_embedded() {
+        let doc = convert_markdown_to_typst(
+            "Inline ![A checkerboard](images/checker.png) image",
+            &FrontMatter::default(),
+            &ConvertOptions {
+                base_dir: Some(fixtures_dir()),
+                ..ConvertOptions::default()
+            },
+        )
+        .expect("conversion should succeed");
+
+        assert!(
+            doc.body
+                .contains("#image(\"/images/checker.png\", alt: \"A checkerboard\")")
+        );
+        assert_eq!(doc.assets.len(), 1);
+        assert_eq!(doc.assets[0].virtual_path, "/images/checker.png");
+        assert!(doc.warnings.is_empty());
+    }
+
+    #[test]
+    fn image_outside_base_dir_is_flattened() {
+        let doc = convert_markdown_to_typst(
+            "![](../fixtures/images/checker.svg) and ![](images/checker.svg)",
+            &FrontMatter::default(),
+            &ConvertOptions {
+                base_dir: Some(fixtures_dir()),
+                ..ConvertOptions::default()
+            },
+        )
+        .expect("conversion should succeed");
+
+        assert!(doc.body.contains("#image(\"/assets/checker.svg\")"));
+        assert!(doc.body.contains("#image(\"/images/checker.svg\")"));
+        assert_eq!(doc.assets.len(), 2);
+    }
+
+    #[test]
+    fn missing_image_warns_with_line() {
+        let doc = convert_markdown_to_typst(
+            "# Title\n\nSee ![diagram](nowhere.png) here.",
+            &FrontMatter::default(),
+            &ConvertOptions {
+                base_dir: Some(fixtures_dir()),
+                line_offset: 4,
+                ..ConvertOptions::default()
+            },
+        )
+        .expect("conversion should succeed");
+
+        assert_eq!(doc.warnings.len(), 1);
+        assert_eq!(doc.warnings[0].line, 7);
+        assert!(doc.warnings[0].message.contains("nowhere.png"));
+        assert!(doc.body.contains("#link(\"nowhere.png\")[diagram]"));
+    }
+
+    #[test]
+    fn standalone_image_becomes_labelled_figure() {
+        let doc = convert_markdown_to_typst(
+            "![Architecture](images/checker.png \"System overview\"){#fig:arch}\n\nSee @fig:arch and [@fig:arch].",
+            &FrontMatter::default(),
+            &ConvertOptions {
+                base_dir: Some(fixtures_dir()),
+                ..ConvertOptions::default()
+            },
+        )
+        .expect("conversion should succeed");
+
+        assert!(
+            doc.body
+                .contains("#figure(\n  image(\"/images/checker.png\"")
+        );
+        assert!(doc.body.contains("caption: [System overview]"));
+        assert!(doc.body.contains(") <fig:arch>"));
+        assert!(doc.body.contains("See @fig:arch and @fig:arch."));
+        assert!(!doc.body.contains("{"));
+    }
+
+    #[test]
+    fn standalone_image_without_title_uses_alt_caption() {
+        let doc = convert("![A diagram](missing.png)");
+        assert!(
+            doc.body
+                .contains("#figure(\n  link(\"missing.png\")[A diagram],")
+        );
+        assert!(doc.body.contains("caption: [A diagram]"));
+    }
+
+    #[test]
+    fn table_caption_and_label() {
+        let doc = convert(
+            "| A | B |\n|---|---|\n| 1 | 2 |\n\nTable: Results by *run* {#tbl:results}\n\nAs @tbl:results shows.",
+        );
+        assert!(doc.body.contains("#figure(\n  table(\n    columns: 2,"));
+        assert!(doc.body.contains("caption: [Results by _run_]"));
+        assert!(doc.body.contains(") <tbl:results>"));
+        assert!(doc.body.contains("As @tbl:results shows."));
+        assert!(!doc.body.contains("Table:"));
+    }
+
+    #[test]
+    fn colon_paragraph_after_table_stays_in_the_body() {
+        let doc = convert("| A | B |\n|---|---|\n| 1 | 2 |\n\n: not a caption");
+        assert!(!doc.body.contains("#figure("));
+        assert!(doc.body.contains(": not a caption"));
+    }
+
+    #[test]
+    fn titled_image_within_text_stays_inline() {
+        let doc = convert("Before ![icon](missing.png \"Icon\"){#fig:icon} after.");
+        assert!(!doc.body.contains("#figure("));
+        assert!(doc.body.contains("Before #link(\"missing.png\")[icon]"));
+        assert!(doc.body.contains("after."));
+    }
+
+    #[test]
+    fn code_listing_caption_and_label() {
+        let doc = convert(
+            "```rust {#lst:main}\nfn main() {}\n```\n\nListing: Entry point\n\nSee @lst:main.",
+        );
+        assert!(
+            doc.body
+                .contains("#figure(\n  ```rust\n  fn main() {}\n  ```,")
+        );
+        assert!(doc.body.contains("caption: [Entry point]"));
+        assert!(doc.body.contains(") <lst:main>"));
+        assert!(doc.body.contains("See @lst:main."));
+    }
+
+    #[test]
+    fn unknown_references_are_escaped() {
+        let doc = convert("Ping @nobody or mail me at someone@example");
+        assert!(doc.body.contains("Ping \\@nobody"));
+        assert!(doc.body.contains("someone\\@example"));
+    }
+
+    #[test]
+    fn remote_image_is_registered_as_asset() {
+        let doc = convert("![badge](https://ci.example.com/badges/build.svg?branch=main)");
+        assert_eq!(doc.assets.len(), 1);
+        assert_eq!(doc.assets[0].virtual_path, "/remote/build");
+        assert_eq!(
+            doc.assets[0].source,
+            AssetSource::Remote("https://ci.example.com/badges/build.svg?branch=main".into())
+        );
+        assert!(doc.warnings.is_empty());
+    }
+
+    fn fixtures_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
     }
 
     #[test]
@@ -945,6 +1769,7 @@ mod tests {
                 author_override: None,
                 lang_override: None,
                 force_toc: None,
+                ..ConvertOptions::default()
             },
         )
         .expect("conversion should succeed");
@@ -963,6 +1788,7 @@ mod tests {
                 author_override: None,
                 lang_override: None,
                 force_toc: Some(true),
+                ..ConvertOptions::default()
             },
         )
         .expect("conversion should succeed");
@@ -980,10 +1806,40 @@ mod tests {
                 author_override: None,
                 lang_override: None,
                 force_toc: None,
+                ..ConvertOptions::default()
             },
         )
         .expect("conversion should succeed");
 
         assert!(doc.body.contains("#footnote[Content."));
     }
+
+    #[test]
+    fn document_text_keeps_roles_and_positions() {
+        let runs = document_text(
+            "# Title\n\nSome *émphasis* and `code`, $x$.\n\n```\nfn main() {}\n```\n",
+            2,
+        );
+        let run = |text: &str| runs.iter().find(|run| run.text == text).unwrap();
+
+        assert_eq!(run("Title").role, TextRole::Heading);
+        assert_eq!(run("Title").position, SourcePosition { line: 3, column: 3 });
+        assert_eq!(
+            run("émphasis").position,
+            SourcePosition { line: 5, column: 7 }
+        );
+        assert_eq!(run("code").role, TextRole::Code);
+        assert_eq!(
+            run("code").position,
+            SourcePosition {
+                line: 5,
+                column: 23
+            }
+        );
+        assert_eq!(
+            run("fn main() {}").position,
+            SourcePosition { line: 8, column: 1 }
+        );
+        assert!(!runs.iter().any(|run| run.text.contains('x')));
+    }
 }
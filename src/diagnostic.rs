@@ -0,0 +1,310 @@
+//! Structured diagnostics and the source map that ties generated Typst code
+//! back to the Markdown it came from.
+
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Pipeline stage a diagnostic was raised in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Frontmatter,
+    Convert,
+    Compile,
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Frontmatter => write!(f, "frontmatter"),
+            Self::Convert => write!(f, "convert"),
+            Self::Compile => write!(f, "compile"),
+        }
+    }
+}
+
+/// A 1-based line and column in the Markdown source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SourcePosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Maps byte offsets in generated Typst code to the Markdown positions they
+/// were rendered from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// `(offset, position)` pairs sorted by offset. An offset maps to the
+    /// closest entry at or before it.
+    entries: Vec<(usize, SourcePosition)>,
+}
+
+impl SourceMap {
+    pub(crate) fn push(&mut self, offset: usize, position: SourcePosition) {
+        self.entries.push((offset, position));
+    }
+
+    /// The Markdown position for `offset`, or `None` if it precedes every
+    /// mapped node (e.g. lies in the template).
+    pub fn lookup(&self, offset: usize) -> Option<SourcePosition> {
+        let index = self.entries.partition_point(|(start, _)| *start <= offset);
+        index.checked_sub(1).map(|index| self.entries[index].1)
+    }
+
+    /// Shift every offset by `offset`, for when the mapped code is embedded
+    /// at that position in a larger source (see
+    /// [`crate::template::body_offset`]).
+    pub fn shifted(mut self, offset: usize) -> Self {
+        for (start, _) in &mut self.entries {
+            *start += offset;
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// A problem found while converting or compiling a document.
+///
+/// Serializes flat, with `line` and `column` in place of `position`, for
+/// `--message-format json`. Both keys are always present, `null` without a
+/// position, so every record has the same shape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub stage: Stage,
+    pub message: String,
+    pub hints: Vec<String>,
+    /// Markdown file the diagnostic refers to, when known.
+    pub file: Option<PathBuf>,
+    /// Position in the Markdown source, when the problem maps back to it.
+    pub position: Option<SourcePosition>,
+    /// 1-based line in the generated Typst source.
+    pub typst_line: Option<usize>,
+    /// The generated Typst code the problem points at.
+    pub typst_snippet: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(stage: Stage, severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            stage,
+            message: message.into(),
+            hints: Vec::new(),
+            file: None,
+            position: None,
+            typst_line: None,
+            typst_snippet: None,
+        }
+    }
+
+    /// Render rustc-style, quoting the offending line of `markdown` (the full
+    /// text of the source file):
+    ///
+    /// ```text
+    /// error: unknown variable: foo
+    ///  --> notes.md:12:3
+    ///    |
+    /// 12 | Some $foo$ math
+    ///    |      ^
+    ///    = note: generated Typst: `$foo$`
+    /// ```
+    pub fn render(&self, source_name: &str, markdown: &str) -> String {
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+
+        let excerpt = match (self.position, self.typst_line) {
+            (Some(position), _) => {
+                let text = markdown.lines().nth(position.line.saturating_sub(1));
+                Some((
+                    format!("{source_name}:{}:{}", position.line, position.column),
+                    position.line,
+                    text.map(|text| (text, position.column)),
+                ))
+            }
+            (None, Some(line)) => Some((
+                format!("{source_name} (generated Typst line {line})"),
+                line,
+                None,
+            )),
+            (None, None) => None,
+        };
+
+        let gutter = excerpt
+            .as_ref()
+            .map_or(0, |(_, line, _)| line.to_string().len());
+        let pad = " ".repeat(gutter);
+
+        if let Some((location, line, text)) = &excerpt {
+            out.push_str(&format!("{pad}--> {location}\n"));
+            if let Some((text, column)) = text {
+                let caret_offset = text
+                    .char_indices()
+                    .take_while(|(index, _)| *index < column.saturating_sub(1))
+                    .count();
+                out.push_str(&format!("{pad} |\n"));
+                out.push_str(&format!("{line} | {text}\n"));
+                out.push_str(&format!("{pad} | {}^\n", " ".repeat(caret_offset)));
+            }
+        }
+
+        for hint in &self.hints {
+            out.push_str(&format!("{pad} = hint: {hint}\n"));
+        }
+        if let Some(snippet) = &self.typst_snippet {
+            out.push_str(&format!("{pad} = note: generated Typst: `{snippet}`\n"));
+        }
+        out
+    }
+}
+
+impl Serialize for Diagnostic {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut record = serializer.serialize_struct("Diagnostic", 9)?;
+        record.serialize_field("severity", &self.severity)?;
+        record.serialize_field("stage", &self.stage)?;
+        record.serialize_field("message", &self.message)?;
+        record.serialize_field("hints", &self.hints)?;
+        record.serialize_field("file", &self.file)?;
+        record.serialize_field("line", &self.position.map(|position| position.line))?;
+        record.serialize_field("column", &self.position.map(|position| position.column))?;
+        record.serialize_field("typst_line", &self.typst_line)?;
+        record.serialize_field("typst_snippet", &self.typst_snippet)?;
+        record.end()
+    }
+}
+
+/// Short single-line form, e.g. `unknown variable: foo (line 12)`, followed
+/// by any hints.
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        match (self.position, self.typst_line) {
+            (Some(position), _) => write!(f, " (line {})", position.line)?,
+            (None, Some(line)) => write!(f, " (generated Typst line {line})")?,
+            (None, None) => {}
+        }
+        for hint in &self.hints {
+            write!(f, "\n  hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(line: usize, column: usize) -> SourcePosition {
+        SourcePosition { line, column }
+    }
+
+    #[test]
+    fn lookup_uses_closest_preceding_entry() {
+        let mut map = SourceMap::default();
+        map.push(0, position(1, 1));
+        map.push(10, position(3, 1));
+        map.push(10, position(3, 5));
+
+        assert_eq!(map.lookup(4), Some(position(1, 1)));
+        assert_eq!(map.lookup(10), Some(position(3, 5)));
+        assert_eq!(map.lookup(99), Some(position(3, 5)));
+
+        let shifted = map.shifted(100);
+        assert_eq!(shifted.lookup(50), None);
+        assert_eq!(shifted.lookup(104), Some(position(1, 1)));
+    }
+
+    #[test]
+    fn renders_rustc_style_excerpt() {
+        let diagnostic = Diagnostic {
+            hints: vec!["check the LaTeX command".to_string()],
+            position: Some(position(2, 6)),
+            typst_snippet: Some("$foo$".to_string()),
+            ..Diagnostic::new(Stage::Compile, Severity::Error, "unknown variable: foo")
+        };
+        assert_eq!(
+            diagnostic.render("notes.md", "# Title\nSome $\\foo$ math\n"),
+            "error: unknown variable: foo\n --> notes.md:2:6\n  |\n2 | Some $\\foo$ math\n  |      ^\n  = hint: check the LaTeX command\n  = note: generated Typst: `$foo$`\n"
+        );
+    }
+
+    #[test]
+    fn renders_generated_location_without_source_position() {
+        let diagnostic = Diagnostic {
+            typst_line: Some(40),
+            ..Diagnostic::new(Stage::Compile, Severity::Warning, "unknown font family: x")
+        };
+        assert_eq!(
+            diagnostic.render("notes.md", ""),
+            "warning: unknown font family: x\n  --> notes.md (generated Typst line 40)\n"
+        );
+        assert_eq!(
+            diagnostic.to_string(),
+            "unknown font family: x (generated Typst line 40)"
+        );
+    }
+
+    #[test]
+    fn serializes_position_flat() {
+        let diagnostic = Diagnostic {
+            file: Some(PathBuf::from("notes.md")),
+            position: Some(position(3, 7)),
+            ..Diagnostic::new(Stage::Convert, Severity::Warning, "image not found")
+        };
+        assert_eq!(
+            serde_json::to_value(&diagnostic).unwrap(),
+            serde_json::json!({
+                "severity": "warning",
+                "stage": "convert",
+                "message": "image not found",
+                "hints": [],
+                "file": "notes.md",
+                "line": 3,
+                "column": 7,
+                "typst_line": null,
+                "typst_snippet": null,
+            })
+        );
+    }
+
+    #[test]
+    fn serializes_missing_position_as_null() {
+        let diagnostic = Diagnostic::new(Stage::Compile, Severity::Error, "file not found");
+        assert_eq!(
+            serde_json::to_value(&diagnostic).unwrap(),
+            serde_json::json!({
+                "severity": "error",
+                "stage": "compile",
+                "message": "file not found",
+                "hints": [],
+                "file": null,
+                "line": null,
+                "column": null,
+                "typst_line": null,
+                "typst_snippet": null,
+            })
+        );
+    }
+}
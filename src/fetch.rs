@@ -0,0 +1,280 @@
+//! Remote asset fetching with an on-disk cache.
+//!
+//! Downloaded files are stored under `~/.mdxport/cache`, keyed by the SHA-256
+//! of their URL. When the server sent an `ETag`, later fetches revalidate
+//! with `If-None-Match` instead of downloading again.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// Offline mode is on and the URL is not cached.
+    NotCached,
+    Http(String),
+    Io(io::Error),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotCached => write!(f, "not available in the offline cache"),
+            Self::Http(err) => write!(f, "{err}"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheEntry {
+    url: String,
+    etag: Option<String>,
+}
+
+pub struct AssetFetcher {
+    client: reqwest::blocking::Client,
+    cache_dir: PathBuf,
+    offline: bool,
+}
+
+impl AssetFetcher {
+    pub fn new(cache_dir: PathBuf, offline: bool) -> Self {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .unwrap_or_default();
+        Self {
+            client,
+            cache_dir,
+            offline,
+        }
+    }
+
+    /// `~/.mdxport/cache`, or `None` if the home directory is unknown.
+    pub fn default_cache_dir() -> Option<PathBuf> {
+        home_dir().map(|home| home.join(".mdxport").join("cache"))
+    }
+
+    /// Return the contents of `url`, from the cache when possible.
+    pub fn fetch(&self, url: &str) -> Result<Vec<u8>, FetchError> {
+        let data_path = self.cache_dir.join(cache_key(url));
+        let meta_path = data_path.with_extension("json");
+        let cached = read_entry(&meta_path).and_then(|entry| {
+            let data = fs::read(&data_path).ok()?;
+            Some((entry, data))
+        });
+
+        if self.offline {
+            return cached.map(|(_, data)| data).ok_or(FetchError::NotCached);
+        }
+
+        let etag = match cached {
+            Some((CacheEntry { etag: None, .. }, data)) => return Ok(data),
+            Some((
+                CacheEntry {
+                    etag: Some(etag), ..
+                },
+                _,
+            )) => Some(etag),
+            None => None,
+        };
+
+        let mut request = self.client.get(url);
+        if let Some(etag) = &etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+
+        let response = match request.send() {
+            Ok(response) => response,
+            // Keep working from the cache when the network is unavailable
+            Err(err) => return fs::read(&data_path).map_err(|_| FetchError::Http(err.to_string())),
+        };
+
+        if response.status() == StatusCode::NOT_MODIFIED && etag.is_some() {
+            return fs::read(&data_path).map_err(FetchError::Io);
+        }
+        if !response.status().is_success() {
+            return Err(FetchError::Http(format!(
+                "server returned {}",
+                response.status()
+            )));
+        }
+
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+        let data = response
+            .bytes()
+            .map_err(|err| FetchError::Http(err.to_string()))?
+            .to_vec();
+
+        // A failed cache write only costs a future download
+        let _ = write_entry(
+            &data_path,
+            &meta_path,
+            &data,
+            &CacheEntry {
+                url: url.to_string(),
+                etag,
+            },
+        );
+
+        Ok(data)
+    }
+}
+
+fn cache_key(url: &str) -> String {
+    Sha256::digest(url.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn read_entry(meta_path: &Path) -> Option<CacheEntry> {
+    let meta = fs::read_to_string(meta_path).ok()?;
+    serde_json::from_str(&meta).ok()
+}
+
+fn write_entry(
+    data_path: &Path,
+    meta_path: &Path,
+    data: &[u8],
+    entry: &CacheEntry,
+) -> io::Result<()> {
+    if let Some(parent) = data_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    write_atomic(data_path, data)?;
+
+    let meta = serde_json::to_vec(entry)
+        .map_err(|error| io::Error::other(format!("serialize cache entry: {error}")))?;
+    write_atomic(meta_path, &meta)
+}
+
+/// Write through a temporary file unique to this process and call, so
+/// parallel conversions fetching the same image never see a partial file.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+    let temp_path = path.with_extension(format!(
+        "{}-{}.part",
+        std::process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&temp_path, data)?;
+    fs::rename(&temp_path, path)
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Minimal HTTP stand-in serving `body` with a fixed ETag. Records the
+    /// `If-None-Match` header of every request it receives.
+    fn serve(body: &'static [u8], requests: usize) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let url = format!("http://{}/badge.svg", listener.local_addr().unwrap());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.expect("accept");
+                let mut if_none_match = None;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("if-none-match: ") {
+                        if_none_match = Some(value.to_string());
+                    }
+                }
+
+                log.lock().unwrap().push(if_none_match.clone());
+                let response = if if_none_match.as_deref() == Some("\"v1\"") {
+                    b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\nContent-Length: 0\r\n\r\n".to_vec()
+                } else {
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(body);
+                    response
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        (url, seen)
+    }
+
+    fn temp_cache_dir() -> PathBuf {
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        std::env::temp_dir().join(format!("mdxport-fetch-test-{}-{nonce}", std::process::id()))
+    }
+
+    #[test]
+    fn downloads_once_then_revalidates_with_etag() {
+        let (url, seen) = serve(b"<svg/>", 2);
+        let cache_dir = temp_cache_dir();
+        let fetcher = AssetFetcher::new(cache_dir.clone(), false);
+
+        assert_eq!(fetcher.fetch(&url).unwrap(), b"<svg/>");
+        assert_eq!(fetcher.fetch(&url).unwrap(), b"<svg/>");
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![None, Some("\"v1\"".to_string())]
+        );
+
+        let _ = fs::remove_dir_all(cache_dir);
+    }
+
+    #[test]
+    fn offline_uses_cache_only() {
+        let (url, _) = serve(b"<svg/>", 1);
+        let cache_dir = temp_cache_dir();
+        AssetFetcher::new(cache_dir.clone(), false)
+            .fetch(&url)
+            .unwrap();
+
+        let offline = AssetFetcher::new(cache_dir.clone(), true);
+        assert_eq!(offline.fetch(&url).unwrap(), b"<svg/>");
+        assert!(matches!(
+            offline.fetch("http://127.0.0.1:9/never-fetched.png"),
+            Err(FetchError::NotCached)
+        ));
+
+        let _ = fs::remove_dir_all(cache_dir);
+    }
+}
@@ -0,0 +1,899 @@
+//! Downloadable font packs for `mdxport fonts install`.
+//!
+//! Pack files are installed flat into `~/.mdxport/fonts`. Their SHA-256
+//! checksums are recorded in a `SHA256SUMS` file there (the format of
+//! `sha256sum`), which `fonts verify` checks the installed files against.
+//! A download is verified before it is installed against the checksum
+//! pinned for it in [`FONT_PACKS`], and against the `SHA256SUMS` its source
+//! publishes, if any: a mirror at its base URL, or a `--from` directory or
+//! zip at its top level.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+/// Name of the checksum list, both in the font directory and in sources.
+pub const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+/// Upper bound for connecting and for each read, so a stalled mirror cannot
+/// hang `fonts install`; slow but steady downloads of large files still
+/// finish.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct FontPack {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Approximate download size in megabytes.
+    pub size_mb: u32,
+    pub files: &'static [PackFile],
+}
+
+pub struct PackFile {
+    /// File name, unique across packs.
+    pub name: &'static str,
+    /// Upstream URL at a tagged release, used unless a mirror or local
+    /// source is given.
+    pub url: &'static str,
+    /// Lowercase hex SHA-256 of the file at `url`, checked whatever the
+    /// source. A file without one is never installed.
+    pub sha256: &'static str,
+}
+
+/// A Noto CJK file from the upstream repository at release `$tag`.
+macro_rules! noto_cjk {
+    ($tag:literal, $style:literal, $dir:literal, $file:literal, $sha256:literal) => {
+        PackFile {
+            name: $file,
+            url: concat!(
+                "https://github.com/notofonts/noto-cjk/raw/",
+                $tag,
+                "/",
+                $style,
+                "/OTF/",
+                $dir,
+                "/",
+                $file
+            ),
+            sha256: $sha256,
+        }
+    };
+}
+
+pub const FONT_PACKS: &[FontPack] = &[
+    FontPack {
+        name: "sc",
+        description: "Simplified Chinese (Noto Sans/Serif CJK SC)",
+        size_mb: 60,
+        files: &[
+            noto_cjk!(
+                "Sans2.004",
+                "Sans",
+                "SimplifiedChinese",
+                "NotoSansCJKsc-Regular.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Sans2.004",
+                "Sans",
+                "SimplifiedChinese",
+                "NotoSansCJKsc-Bold.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Serif2.003",
+                "Serif",
+                "SimplifiedChinese",
+                "NotoSerifCJKsc-Regular.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Serif2.003",
+                "Serif",
+                "SimplifiedChinese",
+                "NotoSerifCJKsc-Bold.otf",
+                ""
+            ),
+        ],
+    },
+    FontPack {
+        name: "tc",
+        description: "Traditional Chinese (Noto Sans/Serif CJK TC)",
+        size_mb: 60,
+        files: &[
+            noto_cjk!(
+                "Sans2.004",
+                "Sans",
+                "TraditionalChinese",
+                "NotoSansCJKtc-Regular.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Sans2.004",
+                "Sans",
+                "TraditionalChinese",
+                "NotoSansCJKtc-Bold.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Serif2.003",
+                "Serif",
+                "TraditionalChinese",
+                "NotoSerifCJKtc-Regular.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Serif2.003",
+                "Serif",
+                "TraditionalChinese",
+                "NotoSerifCJKtc-Bold.otf",
+                ""
+            ),
+        ],
+    },
+    FontPack {
+        name: "jp",
+        description: "Japanese (Noto Sans/Serif CJK JP)",
+        size_mb: 60,
+        files: &[
+            noto_cjk!(
+                "Sans2.004",
+                "Sans",
+                "Japanese",
+                "NotoSansCJKjp-Regular.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Sans2.004",
+                "Sans",
+                "Japanese",
+                "NotoSansCJKjp-Bold.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Serif2.003",
+                "Serif",
+                "Japanese",
+                "NotoSerifCJKjp-Regular.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Serif2.003",
+                "Serif",
+                "Japanese",
+                "NotoSerifCJKjp-Bold.otf",
+                ""
+            ),
+        ],
+    },
+    FontPack {
+        name: "kr",
+        description: "Korean (Noto Sans/Serif CJK KR)",
+        size_mb: 60,
+        files: &[
+            noto_cjk!(
+                "Sans2.004",
+                "Sans",
+                "Korean",
+                "NotoSansCJKkr-Regular.otf",
+                ""
+            ),
+            noto_cjk!("Sans2.004", "Sans", "Korean", "NotoSansCJKkr-Bold.otf", ""),
+            noto_cjk!(
+                "Serif2.003",
+                "Serif",
+                "Korean",
+                "NotoSerifCJKkr-Regular.otf",
+                ""
+            ),
+            noto_cjk!(
+                "Serif2.003",
+                "Serif",
+                "Korean",
+                "NotoSerifCJKkr-Bold.otf",
+                ""
+            ),
+        ],
+    },
+    FontPack {
+        name: "emoji",
+        description: "Color emoji (Noto Color Emoji)",
+        size_mb: 10,
+        files: &[PackFile {
+            name: "NotoColorEmoji.ttf",
+            url: "https://github.com/googlefonts/noto-emoji/raw/v2.047/fonts/NotoColorEmoji.ttf",
+            sha256: "",
+        }],
+    },
+    FontPack {
+        name: "math",
+        description: "Math (STIX Two Math)",
+        size_mb: 1,
+        files: &[PackFile {
+            name: "STIXTwoMath-Regular.otf",
+            url: "https://github.com/stipub/stixfonts/raw/v2.13b171/fonts/static_otf/STIXTwoMath-Regular.otf",
+            sha256: "",
+        }],
+    },
+];
+
+/// The pack installed when none is named.
+pub const DEFAULT_PACK: &str = "sc";
+
+pub fn find_pack(name: &str) -> Option<&'static FontPack> {
+    FONT_PACKS.iter().find(|pack| pack.name == name)
+}
+
+/// Where pack files come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackSource {
+    /// The upstream URLs in [`FONT_PACKS`].
+    Upstream,
+    /// A base URL serving every pack file by name, e.g. a company mirror.
+    Mirror(String),
+    /// A directory containing the pack files (searched recursively), for
+    /// air-gapped machines.
+    Directory(PathBuf),
+    /// A zip archive containing the pack files.
+    Zip(PathBuf),
+}
+
+impl PackSource {
+    /// A local source: a zip archive if `path` is a file, else a directory.
+    pub fn local(path: PathBuf) -> Self {
+        if path.is_file() {
+            Self::Zip(path)
+        } else {
+            Self::Directory(path)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PackError {
+    Http(String),
+    Io(io::Error),
+    /// A local source does not contain a pack file.
+    NotInSource(String),
+    /// A pack file has no pinned checksum to verify it against.
+    Unpinned(String),
+    Checksum {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Display for PackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "{err}"),
+            Self::Io(err) => write!(f, "{err}"),
+            Self::NotInSource(file) => write!(f, "{file} not found in the font source"),
+            Self::Unpinned(file) => write!(f, "no SHA-256 pinned for {file}"),
+            Self::Checksum {
+                file,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "checksum mismatch for {file}: expected {expected}, got {actual}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PackError {}
+
+impl From<io::Error> for PackError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// What [`install`] did with one pack file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Installed {
+    /// Present before; its checksum is recorded if it was not already.
+    AlreadyPresent,
+    /// Installed after matching its pinned checksum, and the one its source
+    /// publishes, if any.
+    New,
+}
+
+/// Install `packs` into `font_dir`, skipping files already there. Installs
+/// nothing if a file has no pinned checksum; otherwise stops at the first
+/// failure, and files installed until then stay installed.
+pub fn install(
+    font_dir: &Path,
+    packs: &[&FontPack],
+    source: &PackSource,
+    mut on_file: impl FnMut(&PackFile, &Installed),
+) -> Result<(), PackError> {
+    if let Some(file) = packs
+        .iter()
+        .flat_map(|pack| pack.files)
+        .find(|file| file.sha256.is_empty())
+    {
+        return Err(PackError::Unpinned(file.name.to_string()));
+    }
+    fs::create_dir_all(font_dir)?;
+    let mut recorded = Checksums::load(&font_dir.join(CHECKSUMS_FILE))?;
+    let client = reqwest::blocking::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .map_err(|e| PackError::Http(e.to_string()))?;
+    let mut published = None;
+
+    for file in packs.iter().flat_map(|pack| pack.files) {
+        let target = font_dir.join(file.name);
+        if target.is_file() {
+            if !recorded.0.contains_key(file.name) {
+                recorded
+                    .0
+                    .insert(file.name.to_string(), hash_file(&target)?);
+                recorded.save(&font_dir.join(CHECKSUMS_FILE))?;
+            }
+            on_file(file, &Installed::AlreadyPresent);
+            continue;
+        }
+
+        let published = match &mut published {
+            Some(published) => published,
+            None => published.insert(published_checksums(&client, source)?),
+        };
+        let temp = target.with_extension("part");
+        let fetched = match source {
+            PackSource::Upstream => download(&client, file.url, file.name, &temp),
+            PackSource::Mirror(base) => download(
+                &client,
+                &format!("{}/{}", base.trim_end_matches('/'), file.name),
+                file.name,
+                &temp,
+            ),
+            PackSource::Directory(dir) => match find_in_dir(dir, file.name) {
+                Some(path) => copy_hashed(&mut fs::File::open(path)?, &temp),
+                None => Err(PackError::NotInSource(file.name.to_string())),
+            },
+            PackSource::Zip(path) => {
+                let mut archive = open_zip(path)?;
+                match zip_index(&archive, file.name) {
+                    Some(index) => copy_hashed(
+                        &mut archive.by_index(index).map_err(io::Error::from)?,
+                        &temp,
+                    ),
+                    None => Err(PackError::NotInSource(file.name.to_string())),
+                }
+            }
+        };
+        let actual = fetched.inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })?;
+
+        if let Some(expected) = [
+            Some(file.sha256),
+            published.0.get(file.name).map(String::as_str),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|expected| !expected.eq_ignore_ascii_case(&actual))
+        {
+            let _ = fs::remove_file(&temp);
+            return Err(PackError::Checksum {
+                file: file.name.to_string(),
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+        fs::rename(&temp, &target)?;
+        recorded.0.insert(file.name.to_string(), actual);
+        recorded.save(&font_dir.join(CHECKSUMS_FILE))?;
+        on_file(file, &Installed::New);
+    }
+    Ok(())
+}
+
+/// Delete the files of `packs` from `font_dir`, returning the names of
+/// those that were installed.
+pub fn remove(font_dir: &Path, packs: &[&FontPack]) -> Result<Vec<&'static str>, PackError> {
+    let checksums_path = font_dir.join(CHECKSUMS_FILE);
+    let mut recorded = Checksums::load(&checksums_path)?;
+    let mut removed = Vec::new();
+    for file in packs.iter().flat_map(|pack| pack.files) {
+        match fs::remove_file(font_dir.join(file.name)) {
+            Ok(()) => removed.push(file.name),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        recorded.0.remove(file.name);
+    }
+    if checksums_path.exists() {
+        recorded.save(&checksums_path)?;
+    }
+    Ok(removed)
+}
+
+/// State of an installed file according to [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileStatus {
+    Ok,
+    /// The file differs from its pinned checksum, or for files of no
+    /// current pack, from the one recorded when it was installed.
+    Modified {
+        expected: String,
+        actual: String,
+    },
+    /// Recorded as installed, but gone.
+    Missing,
+}
+
+/// Check every recorded or installed pack file in `font_dir`, sorted by
+/// file name. Fails if a pack file present has no pinned checksum.
+pub fn verify(font_dir: &Path) -> Result<Vec<(String, FileStatus)>, PackError> {
+    let recorded = Checksums::load(&font_dir.join(CHECKSUMS_FILE))?;
+    let mut expected: BTreeMap<&str, &str> = recorded
+        .0
+        .iter()
+        .map(|(name, hash)| (name.as_str(), hash.as_str()))
+        .collect();
+    for file in FONT_PACKS.iter().flat_map(|pack| pack.files) {
+        if !expected.contains_key(file.name) && !font_dir.join(file.name).is_file() {
+            continue;
+        }
+        if file.sha256.is_empty() {
+            return Err(PackError::Unpinned(file.name.to_string()));
+        }
+        expected.insert(file.name, file.sha256);
+    }
+
+    let mut files = Vec::new();
+    for (name, expected) in expected {
+        let path = font_dir.join(name);
+        let status = if !path.is_file() {
+            FileStatus::Missing
+        } else {
+            let actual = hash_file(&path)?;
+            if actual.eq_ignore_ascii_case(expected) {
+                FileStatus::Ok
+            } else {
+                FileStatus::Modified {
+                    expected: expected.to_string(),
+                    actual,
+                }
+            }
+        };
+        files.push((name.to_string(), status));
+    }
+    Ok(files)
+}
+
+/// Whether every file of `pack` is in `font_dir`.
+pub fn is_installed(font_dir: &Path, pack: &FontPack) -> bool {
+    pack.files
+        .iter()
+        .all(|file| font_dir.join(file.name).is_file())
+}
+
+/// File name → lowercase hex SHA-256, read from and written as `sha256sum`
+/// output.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Checksums(BTreeMap<String, String>);
+
+impl Checksums {
+    fn parse(text: &str) -> Self {
+        Self(
+            text.lines()
+                .filter_map(|line| {
+                    let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+                    // `sha256sum -b` marks binary files with `*`
+                    let name = name.trim_start().trim_start_matches('*');
+                    Some((name.to_string(), hash.to_ascii_lowercase()))
+                })
+                .collect(),
+        )
+    }
+
+    fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let text = self
+            .0
+            .iter()
+            .map(|(name, hash)| format!("{hash}  {name}\n"))
+            .collect::<String>();
+        let temp = path.with_extension("part");
+        fs::write(&temp, text)?;
+        fs::rename(&temp, path)
+    }
+}
+
+/// The checksums `source` publishes, if any.
+fn published_checksums(
+    client: &reqwest::blocking::Client,
+    source: &PackSource,
+) -> Result<Checksums, PackError> {
+    match source {
+        PackSource::Upstream => Ok(Checksums::default()),
+        PackSource::Mirror(base) => {
+            let url = format!("{}/{CHECKSUMS_FILE}", base.trim_end_matches('/'));
+            let response = client
+                .get(&url)
+                .send()
+                .map_err(|e| PackError::Http(format!("{url}: {e}")))?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(Checksums::default());
+            }
+            if !response.status().is_success() {
+                return Err(PackError::Http(format!(
+                    "{url}: server returned {}",
+                    response.status()
+                )));
+            }
+            let text = response
+                .text()
+                .map_err(|e| PackError::Http(format!("{url}: {e}")))?;
+            Ok(Checksums::parse(&text))
+        }
+        PackSource::Directory(dir) => Ok(Checksums::load(&dir.join(CHECKSUMS_FILE))?),
+        PackSource::Zip(path) => {
+            let mut archive = open_zip(path)?;
+            let Some(index) = zip_index(&archive, CHECKSUMS_FILE) else {
+                return Ok(Checksums::default());
+            };
+            let mut text = String::new();
+            archive
+                .by_index(index)
+                .map_err(io::Error::from)?
+                .read_to_string(&mut text)?;
+            Ok(Checksums::parse(&text))
+        }
+    }
+}
+
+/// Download `url` to `destination`, printing progress, and return its
+/// SHA-256.
+fn download(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    file_name: &str,
+    destination: &Path,
+) -> Result<String, PackError> {
+    let mut response = client
+        .get(url)
+        .send()
+        .map_err(|e| PackError::Http(format!("download {file_name}: {e}")))?;
+    if !response.status().is_success() {
+        return Err(PackError::Http(format!(
+            "download {file_name}: server returned {}",
+            response.status()
+        )));
+    }
+
+    let total = response.content_length();
+    let mut output = fs::File::create(destination)?;
+    let mut hasher = Sha256::new();
+    let mut downloaded = 0_u64;
+    let mut buf = [0_u8; 64 * 1024];
+    loop {
+        let count = response.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        output.write_all(&buf[..count])?;
+        hasher.update(&buf[..count]);
+        downloaded += count as u64;
+        print_download_progress(file_name, downloaded, total);
+    }
+    output.flush()?;
+    eprintln!();
+    Ok(hex(&hasher.finalize()))
+}
+
+fn print_download_progress(file_name: &str, downloaded: u64, total: Option<u64>) {
+    match total {
+        Some(total) if total > 0 => {
+            let percent = (downloaded as f64 / total as f64) * 100.0;
+            eprint!("\rDownloading {file_name}: {percent:.1}% ({downloaded}/{total} bytes)");
+        }
+        _ => {
+            eprint!("\rDownloading {file_name}: {downloaded} bytes");
+        }
+    }
+    let _ = io::stderr().flush();
+}
+
+/// Copy `reader` to `destination`, returning the SHA-256 of the data.
+fn copy_hashed(reader: &mut impl Read, destination: &Path) -> Result<String, PackError> {
+    let mut output = fs::File::create(destination)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0_u8; 64 * 1024];
+    loop {
+        let count = reader.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        output.write_all(&buf[..count])?;
+        hasher.update(&buf[..count]);
+    }
+    output.flush()?;
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn find_in_dir(dir: &Path, file_name: &str) -> Option<PathBuf> {
+    let mut entries = fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    entries.sort();
+    let (dirs, files): (Vec<_>, Vec<_>) = entries.into_iter().partition(|path| path.is_dir());
+    files
+        .into_iter()
+        .find(|path| path.file_name().is_some_and(|name| name == file_name))
+        .or_else(|| dirs.iter().find_map(|dir| find_in_dir(dir, file_name)))
+}
+
+fn open_zip(path: &Path) -> io::Result<zip::ZipArchive<io::BufReader<fs::File>>> {
+    zip::ZipArchive::new(io::BufReader::new(fs::File::open(path)?))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("zip: {e}")))
+}
+
+/// Index of the first entry of `archive` whose file name (ignoring
+/// directories) is `file_name`.
+fn zip_index<R: Read + io::Seek>(archive: &zip::ZipArchive<R>, file_name: &str) -> Option<usize> {
+    (0..archive.len()).find(|&index| {
+        archive
+            .name_for_index(index)
+            .and_then(Result::ok)
+            .is_some_and(|name| name.rsplit('/').next() == Some(file_name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A zip archive with deflated entries; ZIP64 records if `zip64`.
+    fn zip_archive(files: &[(&str, &[u8])], zip64: bool) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .large_file(zip64);
+        for (name, data) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mdxport-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const TEST_PACK: FontPack = FontPack {
+        name: "test",
+        description: "test",
+        size_mb: 0,
+        files: &[
+            // SHA-256 of "alpha" and "beta"
+            PackFile {
+                name: "A.otf",
+                url: "",
+                sha256: "8ed3f6ad685b959ead7022518e1af76cd816f8e8ec7ccdda1ed4018e8f2223f8",
+            },
+            PackFile {
+                name: "B.otf",
+                url: "",
+                sha256: "f44e64e75f3948e9f73f8dfa94721c4ce8cbb4f265c4790c702b2d41cfbf2753",
+            },
+        ],
+    };
+
+    #[test]
+    fn reads_entries_from_zip_archives() {
+        let dir = temp_dir("pack-zip-read");
+        for zip64 in [false, true] {
+            let path = dir.join(format!("fonts-{zip64}.zip"));
+            fs::write(
+                &path,
+                zip_archive(&[("fonts/A.otf", b"alpha"), ("B.otf", b"beta")], zip64),
+            )
+            .unwrap();
+            let mut archive = open_zip(&path).unwrap();
+            for (name, data) in [("A.otf", b"alpha".as_slice()), ("B.otf", b"beta")] {
+                let index = zip_index(&archive, name).unwrap();
+                let mut read = Vec::new();
+                archive
+                    .by_index(index)
+                    .unwrap()
+                    .read_to_end(&mut read)
+                    .unwrap();
+                assert_eq!(read, data);
+            }
+            assert_eq!(zip_index(&archive, "C.otf"), None);
+        }
+        fs::write(dir.join("bad.zip"), b"not a zip").unwrap();
+        assert!(open_zip(&dir.join("bad.zip")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn installs_from_a_directory_verifies_and_removes() {
+        let source = temp_dir("pack-source");
+        let fonts = temp_dir("pack-fonts");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        fs::write(source.join("A.otf"), b"alpha").unwrap();
+        fs::write(source.join("nested").join("B.otf"), b"beta").unwrap();
+        fs::write(
+            source.join(CHECKSUMS_FILE),
+            format!("{}  A.otf\n", hex(&Sha256::digest(b"alpha"))),
+        )
+        .unwrap();
+
+        let mut seen = Vec::new();
+        install(
+            &fonts,
+            &[&TEST_PACK],
+            &PackSource::local(source.clone()),
+            |file, installed| seen.push((file.name, installed.clone())),
+        )
+        .unwrap();
+        assert_eq!(
+            seen,
+            [("A.otf", Installed::New), ("B.otf", Installed::New),]
+        );
+        assert!(is_installed(&fonts, &TEST_PACK));
+        assert_eq!(
+            verify(&fonts).unwrap(),
+            [
+                ("A.otf".to_string(), FileStatus::Ok),
+                ("B.otf".to_string(), FileStatus::Ok),
+            ]
+        );
+
+        fs::write(fonts.join("B.otf"), b"tampered").unwrap();
+        assert!(matches!(
+            verify(&fonts).unwrap()[1].1,
+            FileStatus::Modified { .. }
+        ));
+
+        assert_eq!(remove(&fonts, &[&TEST_PACK]).unwrap(), ["A.otf", "B.otf"]);
+        assert!(verify(&fonts).unwrap().is_empty());
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&fonts).unwrap();
+    }
+
+    #[test]
+    fn rejects_files_with_wrong_checksums() {
+        let source = temp_dir("pack-zip");
+        let fonts = temp_dir("pack-zip-fonts");
+        let sums = format!("{}  A.otf\n", hex(&Sha256::digest(b"expected")));
+        fs::write(
+            source.join("fonts.zip"),
+            zip_archive(
+                &[("A.otf", b"alpha"), ("SHA256SUMS", sums.as_bytes())],
+                false,
+            ),
+        )
+        .unwrap();
+
+        let result = install(
+            &fonts,
+            &[&TEST_PACK],
+            &PackSource::local(source.join("fonts.zip")),
+            |_, _| {},
+        );
+        assert!(matches!(result, Err(PackError::Checksum { file, .. }) if file == "A.otf"));
+        assert!(!fonts.join("A.otf").exists());
+        assert!(!fonts.join("A.part").exists());
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&fonts).unwrap();
+    }
+
+    #[test]
+    fn checks_pinned_checksums_without_published_ones() {
+        // SHA-256 of "alpha"
+        const PINNED_PACK: FontPack = FontPack {
+            name: "pinned",
+            description: "pinned",
+            size_mb: 0,
+            files: &[PackFile {
+                name: "A.otf",
+                url: "",
+                sha256: "8ed3f6ad685b959ead7022518e1af76cd816f8e8ec7ccdda1ed4018e8f2223f8",
+            }],
+        };
+        let source = temp_dir("pack-pinned");
+        let fonts = temp_dir("pack-pinned-fonts");
+
+        fs::write(source.join("A.otf"), b"tampered").unwrap();
+        let result = install(
+            &fonts,
+            &[&PINNED_PACK],
+            &PackSource::local(source.clone()),
+            |_, _| {},
+        );
+        assert!(matches!(result, Err(PackError::Checksum { file, .. }) if file == "A.otf"));
+        assert!(!fonts.join("A.otf").exists());
+
+        fs::write(source.join("A.otf"), b"alpha").unwrap();
+        let mut seen = Vec::new();
+        install(
+            &fonts,
+            &[&PINNED_PACK],
+            &PackSource::local(source.clone()),
+            |file, installed| seen.push((file.name, installed.clone())),
+        )
+        .unwrap();
+        assert_eq!(seen, [("A.otf", Installed::New)]);
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&fonts).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_install_unpinned_files() {
+        const UNPINNED_PACK: FontPack = FontPack {
+            name: "unpinned",
+            description: "unpinned",
+            size_mb: 0,
+            files: &[PackFile {
+                name: "C.otf",
+                url: "",
+                sha256: "",
+            }],
+        };
+        let source = temp_dir("pack-unpinned");
+        let fonts = temp_dir("pack-unpinned-fonts");
+        fs::write(source.join("A.otf"), b"alpha").unwrap();
+        fs::write(source.join("C.otf"), b"gamma").unwrap();
+
+        let result = install(
+            &fonts,
+            &[&TEST_PACK, &UNPINNED_PACK],
+            &PackSource::local(source.clone()),
+            |_, _| panic!("nothing may be installed"),
+        );
+        assert!(matches!(result, Err(PackError::Unpinned(file)) if file == "C.otf"));
+        assert!(!fonts.join("A.otf").exists());
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&fonts).unwrap();
+    }
+
+    #[test]
+    fn every_pack_file_is_pinned() {
+        for file in FONT_PACKS.iter().flat_map(|pack| pack.files) {
+            assert!(
+                file.sha256.len() == 64
+                    && file
+                        .sha256
+                        .bytes()
+                        .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f')),
+                "{} has no pinned SHA-256",
+                file.name
+            );
+        }
+    }
+}
@@ -0,0 +1,735 @@
+//! Font discovery. A [`FontSet`] is the set of fonts one compilation can
+//! use: the fonts bundled with Typst, any extra font paths, and (unless
+//! disabled) the fonts installed on the system.
+//!
+//! Scanning reads only font metadata and remembers it in an index at
+//! `~/.mdxport/font-index.json`, keyed by path, size and modification time,
+//! so later runs skip unchanged files. Font data is memory-mapped the first
+//! time Typst asks for a font.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::UNIX_EPOCH;
+
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use typst::foundations::Bytes;
+use typst::text::{Font, FontBook, FontInfo};
+use typst::utils::LazyHash;
+
+use crate::convert::{TextRole, TextRun};
+use crate::diagnostic::{Diagnostic, Severity, SourcePosition, Stage};
+use crate::template::FontFallbacks;
+
+/// Name of the project font directory picked up next to an input file.
+pub const PROJECT_FONT_DIR: &str = "fonts";
+
+/// Which fonts a [`FontSet`] contains.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FontOptions {
+    /// Extra font files or directories (searched recursively). Their fonts
+    /// take precedence over bundled and system fonts of the same family.
+    pub font_paths: Vec<PathBuf>,
+    /// Include system fonts and those installed with `mdxport fonts install`.
+    /// Turn off for hermetic builds that must not depend on the machine.
+    pub system_fonts: bool,
+}
+
+impl Default for FontOptions {
+    fn default() -> Self {
+        Self {
+            font_paths: Vec::new(),
+            system_fonts: true,
+        }
+    }
+}
+
+impl FontOptions {
+    /// Add the `fonts/` directory in `dir`, if there is one.
+    pub fn with_project_fonts(mut self, dir: &Path) -> Self {
+        let fonts = dir.join(PROJECT_FONT_DIR);
+        if fonts.is_dir() && !self.font_paths.contains(&fonts) {
+            self.font_paths.push(fonts);
+        }
+        self
+    }
+
+    /// Add the directory of a multi-file template, so fonts shipped with the
+    /// template are found.
+    pub fn with_template_fonts(mut self, dir: Option<&Path>) -> Self {
+        if let Some(dir) = dir
+            && !self.font_paths.iter().any(|path| path == dir)
+        {
+            self.font_paths.push(dir.to_path_buf());
+        }
+        self
+    }
+}
+
+/// A set of fonts, cheap to clone. Fonts are parsed on first use.
+#[derive(Clone)]
+pub struct FontSet {
+    storage: Arc<FontStorage>,
+}
+
+struct FontStorage {
+    book: LazyHash<FontBook>,
+    fonts: Vec<FontSlot>,
+}
+
+static SHARED: OnceLock<Mutex<HashMap<FontOptions, FontSet>>> = OnceLock::new();
+
+impl FontSet {
+    /// Scan the fonts selected by `options`, using and refreshing the font
+    /// index in `~/.mdxport`.
+    pub fn new(options: &FontOptions) -> Self {
+        Self::with_index(options, default_index_path())
+    }
+
+    fn with_index(options: &FontOptions, index_path: Option<PathBuf>) -> Self {
+        let index = index_path
+            .as_deref()
+            .map(FontIndex::load)
+            .unwrap_or_default();
+        let mut scanner = Scanner::new(index);
+
+        for path in &options.font_paths {
+            if path.is_dir() {
+                scanner.scan_dir(path);
+            } else {
+                scanner.add_file(path);
+            }
+        }
+
+        // Bundled fonts from typst-assets (Libertinus Serif, New CM, DejaVu Sans Mono)
+        for data in typst_assets::fonts() {
+            scanner.add_bundled(data);
+        }
+
+        if options.system_fonts {
+            for dir in system_font_dirs() {
+                scanner.scan_dir(&dir);
+            }
+        }
+
+        if let Some(path) = index_path {
+            scanner.save_index(&path);
+        }
+        Self {
+            storage: Arc::new(FontStorage {
+                book: LazyHash::new(scanner.book),
+                fonts: scanner.fonts,
+            }),
+        }
+    }
+
+    /// Like [`Self::new`], but scanned once per process for each distinct
+    /// `options`, so converting many files does not rescan the disk.
+    pub fn shared(options: &FontOptions) -> Self {
+        let mut sets = SHARED
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        sets.entry(options.clone())
+            .or_insert_with(|| Self::new(options))
+            .clone()
+    }
+
+    pub fn book(&self) -> &LazyHash<FontBook> {
+        &self.storage.book
+    }
+
+    pub fn font(&self, index: usize) -> Option<Font> {
+        self.storage.fonts.get(index)?.get()
+    }
+
+    /// File the font at `index` comes from; `None` for bundled fonts.
+    pub fn path(&self, index: usize) -> Option<&Path> {
+        self.storage.fonts.get(index)?.file.path.as_deref()
+    }
+
+    /// Pick the font for `ch` the way Typst does: the first of `families`
+    /// that covers it, otherwise any font that does.
+    pub fn resolve(&self, families: &[String], ch: char) -> Resolution {
+        let book = self.book();
+        let covers = |index: &usize| {
+            book.info(*index)
+                .is_some_and(|info| info.coverage.contains(ch as u32))
+        };
+        if let Some(index) = families
+            .iter()
+            .find_map(|family| book.select_family(&family.to_lowercase()).find(covers))
+        {
+            return Resolution::Listed(index);
+        }
+        match (0..self.len()).find(covers) {
+            Some(index) => Resolution::Fallback(index),
+            None => Resolution::Missing,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.storage.fonts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.storage.fonts.is_empty()
+    }
+
+    /// Font family names, sorted.
+    pub fn families(&self) -> Vec<String> {
+        self.storage
+            .book
+            .families()
+            .map(|(family, _)| family.to_string())
+            .collect()
+    }
+}
+
+/// Outcome of [`FontSet::resolve`]; indices are font indices in the set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// A font from the requested families covers the character.
+    Listed(usize),
+    /// Only a font outside the requested families does; Typst falls back
+    /// to it.
+    Fallback(usize),
+    /// No font covers the character, so it renders as a box ("tofu").
+    Missing,
+}
+
+/// Check that every character of `text` has a font, resolving each against
+/// the template list for its role. Reports one diagnostic per character at
+/// its first occurrence: an error when no font covers it, a warning when
+/// only a font outside the template's list does.
+pub fn check_coverage(
+    fonts: &FontSet,
+    fallbacks: &FontFallbacks,
+    text: &[TextRun],
+) -> Vec<Diagnostic> {
+    let mut found: Vec<(Diagnostic, usize)> = Vec::new();
+    let mut seen = HashMap::new();
+
+    for run in text {
+        let families = match run.role {
+            TextRole::Heading => &fallbacks.title,
+            TextRole::Body => &fallbacks.body,
+            TextRole::Code => &fallbacks.code,
+        };
+        for (offset, ch) in run.text.char_indices() {
+            if ch.is_whitespace() || ch.is_control() {
+                continue;
+            }
+            let (severity, message) = match fonts.resolve(families, ch) {
+                Resolution::Listed(_) => continue,
+                // Templates without declared lists rely on fallback throughout
+                Resolution::Fallback(_) if families.is_empty() => continue,
+                Resolution::Fallback(index) => (
+                    Severity::Warning,
+                    format!(
+                        "{} is not covered by the template's {} fonts; Typst falls back to {}",
+                        describe_char(ch),
+                        role_name(run.role),
+                        fonts
+                            .book()
+                            .info(index)
+                            .map_or("another font", |info| info.family.as_str())
+                    ),
+                ),
+                Resolution::Missing => (
+                    Severity::Error,
+                    format!("no font covers {}", describe_char(ch)),
+                ),
+            };
+            // Keyed by message: one diagnostic per character (and font list)
+            let index = *seen.entry(message.clone()).or_insert_with(|| {
+                found.push((
+                    Diagnostic {
+                        position: Some(SourcePosition {
+                            line: run.position.line,
+                            column: run.position.column + offset,
+                        }),
+                        ..Diagnostic::new(Stage::Compile, severity, message)
+                    },
+                    0,
+                ));
+                found.len() - 1
+            });
+            found[index].1 += 1;
+        }
+    }
+
+    found
+        .into_iter()
+        .map(|(mut diagnostic, count)| {
+            if diagnostic.severity == Severity::Error {
+                diagnostic.hints.push(
+                    "add a font that covers it, e.g. in a fonts/ directory next to the document"
+                        .to_string(),
+                );
+            }
+            match count {
+                0 | 1 => {}
+                2 => diagnostic.hints.push("1 more occurrence".to_string()),
+                count => diagnostic
+                    .hints
+                    .push(format!("{} more occurrences", count - 1)),
+            }
+            diagnostic
+        })
+        .collect()
+}
+
+fn describe_char(ch: char) -> String {
+    format!("'{ch}' (U+{:04X})", ch as u32)
+}
+
+fn role_name(role: TextRole) -> &'static str {
+    match role {
+        TextRole::Heading => "title",
+        TextRole::Body => "body",
+        TextRole::Code => "code",
+    }
+}
+
+/// The bundled and system fonts, shared process-wide.
+impl Default for FontSet {
+    fn default() -> Self {
+        Self::shared(&FontOptions::default())
+    }
+}
+
+impl Debug for FontSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FontSet")
+            .field("fonts", &self.len())
+            .finish()
+    }
+}
+
+/// One face of a font file. The file is only mapped when Typst first asks
+/// for one of its faces.
+struct FontSlot {
+    file: Arc<FontFile>,
+    index: u32,
+    font: OnceLock<Option<Font>>,
+}
+
+impl FontSlot {
+    fn get(&self) -> Option<Font> {
+        self.font
+            .get_or_init(|| Font::new(self.file.data()?, self.index))
+            .clone()
+    }
+}
+
+/// A font file, shared by the slots of its faces (collections have several).
+struct FontFile {
+    /// `None` for bundled fonts, whose data is always present.
+    path: Option<PathBuf>,
+    data: OnceLock<Option<Bytes>>,
+}
+
+impl FontFile {
+    fn data(&self) -> Option<Bytes> {
+        self.data
+            .get_or_init(|| map_file(self.path.as_deref()?))
+            .clone()
+    }
+}
+
+fn map_file(path: &Path) -> Option<Bytes> {
+    let file = fs::File::open(path).ok()?;
+    // SAFETY: font files are not expected to change while we run. A file
+    // truncated under the mapping would fault; typst-cli and fontdb accept
+    // the same trade-off to avoid copying hundreds of MB of CJK fonts.
+    match unsafe { Mmap::map(&file) } {
+        Ok(mmap) => Some(Bytes::new(mmap)),
+        Err(_) => fs::read(path).ok().map(Bytes::new),
+    }
+}
+
+/// Builds a font book from files, reusing the metadata of files the index
+/// already knows.
+struct Scanner {
+    book: FontBook,
+    fonts: Vec<FontSlot>,
+    index: HashMap<PathBuf, IndexedFile>,
+    /// Canonical paths already added, so each font is loaded once even when
+    /// directories overlap or link to each other.
+    seen: HashSet<PathBuf>,
+    index_changed: bool,
+}
+
+impl Scanner {
+    fn new(index: FontIndex) -> Self {
+        Self {
+            book: FontBook::new(),
+            fonts: Vec::new(),
+            index: index
+                .files
+                .into_iter()
+                .map(|file| (file.path.clone(), file))
+                .collect(),
+            seen: HashSet::new(),
+            index_changed: false,
+        }
+    }
+
+    fn add_bundled(&mut self, data: &'static [u8]) {
+        let data = Bytes::new(data);
+        let faces = parse_faces(&data);
+        let file = FontFile {
+            path: None,
+            data: OnceLock::from(Some(data)),
+        };
+        self.push(file, faces);
+    }
+
+    fn scan_dir(&mut self, dir: &Path) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                self.scan_dir(&path);
+            } else if is_font_file(&path) {
+                self.add_file(&path);
+            }
+        }
+    }
+
+    fn add_file(&mut self, path: &Path) {
+        let Ok(path) = fs::canonicalize(path) else {
+            return;
+        };
+        if !self.seen.insert(path.clone()) {
+            return;
+        }
+        let Ok(metadata) = fs::metadata(&path) else {
+            return;
+        };
+        let (len, modified) = (metadata.len(), modified_nanos(&metadata));
+
+        if let Some(indexed) = self.index.get(&path)
+            && indexed.len == len
+            && indexed.modified == modified
+        {
+            let faces = indexed.faces.clone();
+            let file = FontFile {
+                path: Some(path),
+                data: OnceLock::new(),
+            };
+            self.push(file, faces);
+            return;
+        }
+
+        // New or changed: the mapping used to read the metadata is kept for
+        // when the font is used
+        let data = map_file(&path);
+        let faces = data.as_ref().map(parse_faces).unwrap_or_default();
+        self.index.insert(
+            path.clone(),
+            IndexedFile {
+                path: path.clone(),
+                len,
+                modified,
+                faces: faces.clone(),
+            },
+        );
+        self.index_changed = true;
+        let file = FontFile {
+            path: Some(path),
+            data: OnceLock::from(data),
+        };
+        self.push(file, faces);
+    }
+
+    fn push(&mut self, file: FontFile, faces: Vec<FontInfo>) {
+        let file = Arc::new(file);
+        for (index, info) in (0_u32..).zip(faces) {
+            self.book.push(info);
+            self.fonts.push(FontSlot {
+                file: Arc::clone(&file),
+                index,
+                font: OnceLock::new(),
+            });
+        }
+    }
+
+    /// Write the index back if this scan learned something. Entries for
+    /// files outside this scan are kept (another font configuration may
+    /// need them) unless the file is gone.
+    fn save_index(&mut self, path: &Path) {
+        let before = self.index.len();
+        self.index
+            .retain(|file, _| self.seen.contains(file) || file.exists());
+        if !self.index_changed && self.index.len() == before {
+            return;
+        }
+
+        let mut files = self.index.values().cloned().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let index = FontIndex {
+            version: INDEX_VERSION,
+            files,
+        };
+        if let Ok(json) = serde_json::to_vec(&index) {
+            // The index is only a cache; failing to write it costs a rescan
+            let _ = write_atomic(path, &json);
+        }
+    }
+}
+
+fn parse_faces(data: &Bytes) -> Vec<FontInfo> {
+    (0_u32..)
+        .map_while(|index| FontInfo::new(data.as_slice(), index))
+        .collect()
+}
+
+fn is_font_file(path: &Path) -> bool {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase);
+    matches!(ext.as_deref(), Some("ttf" | "otf" | "ttc" | "otc"))
+}
+
+fn modified_nanos(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// Bump when the index format (or Typst's `FontInfo`) changes; older
+/// indexes are then ignored and rebuilt.
+const INDEX_VERSION: u32 = 1;
+
+/// Font metadata from earlier scans, so unchanged files need not be read.
+#[derive(Default, Serialize, Deserialize)]
+struct FontIndex {
+    version: u32,
+    files: Vec<IndexedFile>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct IndexedFile {
+    path: PathBuf,
+    len: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    modified: u64,
+    faces: Vec<FontInfo>,
+}
+
+impl FontIndex {
+    fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<Self>(&json).ok())
+            .filter(|index| index.version == INDEX_VERSION)
+            .unwrap_or_default()
+    }
+}
+
+fn default_index_path() -> Option<PathBuf> {
+    home_dir().map(|home| home.join(".mdxport").join("font-index.json"))
+}
+
+/// Write via a temporary file and rename, so concurrent runs never see a
+/// half-written index.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = path.with_extension(format!("{}.part", std::process::id()));
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+fn system_font_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+
+    #[cfg(target_os = "macos")]
+    {
+        dirs.push(PathBuf::from("/System/Library/Fonts"));
+        dirs.push(PathBuf::from("/Library/Fonts"));
+        if let Some(home) = home_dir() {
+            dirs.push(home.join("Library/Fonts"));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        dirs.push(PathBuf::from("/usr/share/fonts"));
+        dirs.push(PathBuf::from("/usr/local/share/fonts"));
+        if let Some(home) = home_dir() {
+            dirs.push(home.join(".local/share/fonts"));
+            dirs.push(home.join(".fonts"));
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        if let Some(windir) = std::env::var_os("WINDIR") {
+            dirs.push(PathBuf::from(windir).join("Fonts"));
+        }
+        if let Some(local) = std::env::var_os("LOCALAPPDATA") {
+            dirs.push(PathBuf::from(local).join("Microsoft\\Fonts"));
+        }
+    }
+
+    if let Some(home) = home_dir() {
+        dirs.push(home.join(".mdxport").join("fonts"));
+    }
+
+    dirs
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundled_only(font_paths: Vec<PathBuf>) -> FontSet {
+        FontSet::with_index(
+            &FontOptions {
+                font_paths,
+                system_fonts: false,
+            },
+            None,
+        )
+    }
+
+    #[test]
+    fn without_system_fonts_only_bundled_fonts_remain() {
+        let families = bundled_only(Vec::new()).families();
+        assert!(families.iter().any(|family| family == "Libertinus Serif"));
+        assert!(families.iter().any(|family| family == "DejaVu Sans Mono"));
+        assert_eq!(
+            bundled_only(vec![PathBuf::from("/nonexistent/fonts")]).families(),
+            families
+        );
+    }
+
+    #[test]
+    fn font_paths_come_first() {
+        let dir = std::env::temp_dir().join(format!("mdxport-fonts-{}", std::process::id()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        let font = typst_assets::fonts().next().unwrap();
+        fs::write(dir.join("nested").join("Project.ttf"), font).unwrap();
+
+        let set = bundled_only(vec![dir.clone()]);
+        assert_eq!(set.len(), bundled_only(Vec::new()).len() + 1);
+        assert_eq!(set.book().info(0), FontInfo::new(font, 0).as_ref());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn project_fonts_are_found_next_to_the_input() {
+        let dir = std::env::temp_dir().join(format!("mdxport-project-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert!(
+            FontOptions::default()
+                .with_project_fonts(&dir)
+                .font_paths
+                .is_empty()
+        );
+
+        fs::create_dir_all(dir.join(PROJECT_FONT_DIR)).unwrap();
+        let options = FontOptions::default()
+            .with_project_fonts(&dir)
+            .with_project_fonts(&dir);
+        assert_eq!(options.font_paths, vec![dir.join(PROJECT_FONT_DIR)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_skips_unchanged_files_and_fonts_load_lazily() {
+        let dir = std::env::temp_dir().join(format!("mdxport-index-{}", std::process::id()));
+        let fonts = dir.join("fonts");
+        fs::create_dir_all(&fonts).unwrap();
+        let font = typst_assets::fonts().next().unwrap();
+        fs::write(fonts.join("Project.ttf"), font).unwrap();
+        let index_path = dir.join("font-index.json");
+        let options = FontOptions {
+            font_paths: vec![fonts.clone()],
+            system_fonts: false,
+        };
+
+        let first = FontSet::with_index(&options, Some(index_path.clone()));
+        let mut index = FontIndex::load(&index_path);
+        assert_eq!(index.files.len(), 1);
+        assert_eq!(first.book().info(0), Some(&index.files[0].faces[0]));
+
+        // Metadata now comes from the index alone, without opening the file
+        index.files[0].faces[0].family = "Indexed Only".into();
+        fs::write(&index_path, serde_json::to_vec(&index).unwrap()).unwrap();
+        let second = FontSet::with_index(&options, Some(index_path.clone()));
+        assert!(second.families().contains(&"Indexed Only".to_string()));
+        assert!(second.storage.fonts[0].file.data.get().is_none());
+        assert!(second.font(0).is_some());
+        assert!(second.storage.fonts[0].file.data.get().is_some());
+
+        // A changed file is parsed again
+        fs::write(
+            fonts.join("Project.ttf"),
+            typst_assets::fonts().nth(1).unwrap(),
+        )
+        .unwrap();
+        let third = FontSet::with_index(&options, Some(index_path.clone()));
+        assert!(!third.families().contains(&"Indexed Only".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn coverage_reports_missing_and_fallback_characters() {
+        let fonts = bundled_only(Vec::new());
+        let fallbacks = FontFallbacks {
+            title: Vec::new(),
+            body: vec!["Libertinus Serif".to_string()],
+            code: vec!["Nonexistent Mono".to_string()],
+        };
+        let run = |text: &str, role, line| TextRun {
+            text: text.to_string(),
+            role,
+            position: SourcePosition { line, column: 1 },
+        };
+        let diagnostics = check_coverage(
+            &fonts,
+            &fallbacks,
+            &[
+                run("Café 中", TextRole::Body, 1),
+                run("中", TextRole::Heading, 2),
+                run("x", TextRole::Code, 3),
+            ],
+        );
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].message, "no font covers '中' (U+4E2D)");
+        assert_eq!(
+            diagnostics[0].position,
+            Some(SourcePosition { line: 1, column: 7 })
+        );
+        assert!(
+            diagnostics[0]
+                .hints
+                .contains(&"1 more occurrence".to_string())
+        );
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+        assert!(diagnostics[1].message.contains("template's code fonts"));
+    }
+}
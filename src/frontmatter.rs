@@ -1,4 +1,6 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::diagnostic::{Diagnostic, Severity, SourcePosition, Stage};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct FrontMatter {
@@ -12,17 +14,140 @@ pub struct FrontMatter {
     pub lang: Option<String>,
     #[serde(default)]
     pub toc: Option<bool>,
+    /// Either a YAML list or a comma-separated string.
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub keywords: Vec<String>,
+    #[serde(default)]
+    pub subject: Option<String>,
+    /// Publication date as `YYYY-MM-DD`, optionally followed by a time as in
+    /// `2024-05-01 10:00:00 +0000`. Anything else is warned about and left
+    /// out of the PDF metadata.
+    #[serde(default, deserialize_with = "scalar_string")]
+    pub date: Option<String>,
+    /// Every key of the frontmatter, including the ones above, for
+    /// templates (see [`DocumentMetadata::meta`](crate::convert::DocumentMetadata::meta)).
+    #[serde(skip)]
+    pub fields: serde_yaml::Mapping,
+}
+
+/// A calendar date from frontmatter, e.g. `2024-05-01`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DocumentDate {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl DocumentDate {
+    /// Parse a `YYYY-MM-DD` date. A time after it, separated by a space or
+    /// `T`, is ignored.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let date = value
+            .split_once([' ', 'T', 't'])
+            .map_or(value, |(date, _)| date);
+        let mut parts = date.splitn(3, '-');
+        let year = parts.next()?.parse().ok()?;
+        let month = parts.next()?.parse().ok()?;
+        let day = parts.next()?.parse().ok()?;
+        let days_in_month = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+            2 => 28,
+            _ => return None,
+        };
+        (1..=days_in_month)
+            .contains(&day)
+            .then_some(Self { year, month, day })
+    }
+
+    /// Seconds since the Unix epoch at midnight UTC of this date.
+    pub fn unix_timestamp(self) -> i64 {
+        // Days-from-civil, see http://howardhinnant.github.io/date_algorithms.html
+        let year = i64::from(self.year) - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let month = i64::from(self.month);
+        let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+            + i64::from(self.day)
+            - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        (era * 146_097 + doe - 719_468) * 86_400
+    }
+}
+
+/// A comma-separated string or a list; numbers and booleans are taken as
+/// their text, e.g. `keywords: [2024, q3]`.
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde_yaml::Value;
+
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::Sequence(values)) => values.iter().filter_map(scalar_text).collect(),
+        Some(value) => scalar_text(&value)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|keyword| !keyword.is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+        None => Vec::new(),
+    })
+}
+
+/// Any YAML scalar as text, so that e.g. `date: 2024` reaches the date check
+/// instead of failing to deserialize.
+fn scalar_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<serde_yaml::Value>::deserialize(deserializer)?;
+    Ok(value.map(|value| {
+        scalar_text(&value).unwrap_or_else(|| {
+            serde_yaml::to_string(&value)
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        })
+    }))
+}
+
+fn scalar_text(value: &serde_yaml::Value) -> Option<String> {
+    use serde_yaml::Value;
+
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Tagged(tagged) => scalar_text(&tagged.value),
+        Value::Null | Value::Sequence(_) | Value::Mapping(_) => None,
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ParsedMarkdown {
     pub frontmatter: FrontMatter,
     pub body: String,
+    /// Number of lines the frontmatter block occupied before `body`.
+    pub body_line_offset: usize,
+    /// Problems that do not stop the conversion, e.g. an unparseable date.
+    pub warnings: Vec<Diagnostic>,
 }
 
 #[derive(Debug)]
 pub struct FrontMatterError {
     message: String,
+    position: Option<SourcePosition>,
+}
+
+impl FrontMatterError {
+    /// Where in the Markdown file the problem is, when known.
+    pub fn position(&self) -> Option<SourcePosition> {
+        self.position
+    }
 }
 
 impl std::fmt::Display for FrontMatterError {
@@ -33,6 +158,15 @@ impl std::fmt::Display for FrontMatterError {
 
 impl std::error::Error for FrontMatterError {}
 
+impl From<&FrontMatterError> for Diagnostic {
+    fn from(error: &FrontMatterError) -> Self {
+        Self {
+            position: error.position,
+            ..Diagnostic::new(Stage::Frontmatter, Severity::Error, error.message.clone())
+        }
+    }
+}
+
 pub fn split_frontmatter(input: &str) -> Result<ParsedMarkdown, FrontMatterError> {
     let normalized = input.trim_start_matches('\u{feff}');
     let mut lines = normalized.lines();
@@ -42,15 +176,19 @@ pub fn split_frontmatter(input: &str) -> Result<ParsedMarkdown, FrontMatterError
         return Ok(ParsedMarkdown {
             frontmatter: FrontMatter::default(),
             body: normalized.to_string(),
+            body_line_offset: 0,
+            warnings: Vec::new(),
         });
     }
 
     let mut frontmatter_block = String::new();
     let mut found_end = false;
     let mut remaining_lines: Vec<&str> = Vec::new();
+    let mut body_line_offset = 1;
 
     for line in lines {
         if !found_end {
+            body_line_offset += 1;
             if line == "---" {
                 found_end = true;
                 continue;
@@ -65,19 +203,70 @@ pub fn split_frontmatter(input: &str) -> Result<ParsedMarkdown, FrontMatterError
     if !found_end {
         return Err(FrontMatterError {
             message: "frontmatter must have opening and closing ---".to_string(),
+            position: Some(SourcePosition { line: 1, column: 1 }),
         });
     }
 
     let mut frontmatter = FrontMatter::default();
     if !frontmatter_block.trim().is_empty() {
-        frontmatter = serde_yaml::from_str(&frontmatter_block).map_err(|e| FrontMatterError {
-            message: format!("yaml parse error: {e}"),
+        frontmatter = serde_yaml::from_str(&frontmatter_block).map_err(|e| {
+            // serde_yaml reports positions within the block, which starts on
+            // the line after the opening ---
+            let location = e.location();
+            let message = e.to_string();
+            let message = location
+                .as_ref()
+                .and_then(|location| {
+                    message.strip_suffix(&format!(
+                        " at line {} column {}",
+                        location.line(),
+                        location.column()
+                    ))
+                })
+                .unwrap_or(&message);
+            FrontMatterError {
+                message: format!("yaml parse error: {message}"),
+                position: location.map(|location| SourcePosition {
+                    line: location.line() + 1,
+                    column: location.column(),
+                }),
+            }
         })?;
     }
 
+    // Parsing succeeded above, so the block is valid YAML
+    if let Ok(serde_yaml::Value::Mapping(fields)) = serde_yaml::from_str(&frontmatter_block) {
+        frontmatter.fields = fields;
+    }
+
+    // The date only feeds the PDF metadata, so a bad one is dropped there
+    // rather than failing the document
+    let mut warnings = Vec::new();
+    if let Some(date) = &frontmatter.date
+        && DocumentDate::parse(date).is_none()
+    {
+        warnings.push(Diagnostic {
+            position: frontmatter_block
+                .lines()
+                .position(|line| line.starts_with("date:"))
+                .map(|index| SourcePosition {
+                    line: index + 2,
+                    column: 1,
+                }),
+            hints: vec!["the date is left out of the PDF metadata".to_string()],
+            ..Diagnostic::new(
+                Stage::Frontmatter,
+                Severity::Warning,
+                format!("invalid date `{date}`, expected YYYY-MM-DD"),
+            )
+        });
+    }
+
     Ok(ParsedMarkdown {
         frontmatter,
         body: remaining_lines.join("\n"),
+        body_line_offset,
+        warnings,
     })
 }
 
@@ -108,6 +297,16 @@ mod tests {
         assert_eq!(parsed.frontmatter.lang.as_deref(), Some("zh"));
         assert_eq!(parsed.frontmatter.toc, Some(true));
         assert_eq!(parsed.body, "Body");
+        assert_eq!(parsed.body_line_offset, 6);
+    }
+
+    #[test]
+    fn keeps_every_field() {
+        let input = "---\ntitle: T\ndepartment: R&D\nreview:\n  round: 2\n---\nBody";
+        let fields = split_frontmatter(input).unwrap().frontmatter.fields;
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields["department"], "R&D");
+        assert_eq!(fields["review"]["round"], 2);
     }
 
     #[test]
@@ -136,6 +335,77 @@ mod tests {
         assert_eq!(parsed.frontmatter.title.as_deref(), Some("BOM"));
     }
 
+    #[test]
+    fn document_metadata_keys() {
+        let input = "---\nkeywords: typst, pdf ,\nsubject: Export\ndate: 2024-02-29\n---\nBody";
+        let parsed = split_frontmatter(input).unwrap();
+        assert_eq!(parsed.frontmatter.keywords, vec!["typst", "pdf"]);
+        assert_eq!(parsed.frontmatter.subject.as_deref(), Some("Export"));
+        assert_eq!(
+            parsed
+                .frontmatter
+                .date
+                .as_deref()
+                .and_then(DocumentDate::parse),
+            Some(DocumentDate {
+                year: 2024,
+                month: 2,
+                day: 29
+            })
+        );
+
+        let parsed = split_frontmatter("---\nkeywords: [a, b]\n---\n").unwrap();
+        assert_eq!(parsed.frontmatter.keywords, vec!["a", "b"]);
+
+        let parsed = split_frontmatter("---\nkeywords: [1, 2.5, true]\n---\n").unwrap();
+        assert_eq!(parsed.frontmatter.keywords, vec!["1", "2.5", "true"]);
+        let parsed = split_frontmatter("---\nkeywords: 2024\n---\n").unwrap();
+        assert_eq!(parsed.frontmatter.keywords, vec!["2024"]);
+    }
+
+    #[test]
+    fn date_to_unix_timestamp() {
+        let date = |value| DocumentDate::parse(value).unwrap().unix_timestamp();
+        assert_eq!(date("1970-01-01"), 0);
+        assert_eq!(date("2024-02-29"), 1_709_164_800);
+        assert_eq!(date("1969-12-31"), -86_400);
+    }
+
+    #[test]
+    fn dates_with_times_keep_their_day() {
+        let expected = Some(DocumentDate {
+            year: 2024,
+            month: 5,
+            day: 1,
+        });
+        assert_eq!(DocumentDate::parse("2024-05-01 10:00:00 +0000"), expected);
+        assert_eq!(DocumentDate::parse("2024-05-01T10:00:00Z"), expected);
+        let parsed = split_frontmatter("---\ndate: 2024-05-01 10:00:00 +0000\n---\n").unwrap();
+        assert!(parsed.warnings.is_empty());
+    }
+
+    #[test]
+    fn invalid_dates_are_warnings() {
+        for date in ["2023-02-29", "May 1st", "2024"] {
+            let parsed = split_frontmatter(&format!("---\ndate: {date}\n---\nBody")).unwrap();
+            assert_eq!(parsed.warnings.len(), 1, "{date}");
+            assert_eq!(parsed.warnings[0].severity, Severity::Warning);
+            assert_eq!(parsed.body, "Body");
+        }
+    }
+
+    #[test]
+    fn errors_carry_file_positions() {
+        let parsed = split_frontmatter("---\ntitle: A\ndate: 2023-02-29\n---\nBody").unwrap();
+        assert_eq!(
+            parsed.warnings[0].position,
+            Some(SourcePosition { line: 3, column: 1 })
+        );
+
+        let error = split_frontmatter("---\ntitle: A\nauthors: [a\n---\nBody").unwrap_err();
+        assert_eq!(error.position().map(|position| position.line), Some(4));
+    }
+
     #[test]
     fn unknown_fields_ignored() {
         let input = "---\ntitle: Test\ncustom_field: whatever\n---\nBody";
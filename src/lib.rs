@@ -37,25 +37,43 @@
 //!     converted.toc,
 //!     &converted.body,
 //! );
-//! let pdf = compile::compile_typst_to_pdf(
+//! let pdf = compile::compile_typst_to_bytes(
 //!     &typst_source,
-//!     std::path::Path::new("output.pdf"),
+//!     &compile::CompileOptions::default(),
 //! ).unwrap();
 //! ```
 
 pub mod compile;
 pub mod convert;
+pub mod diagnostic;
+#[cfg(feature = "cli")]
+pub mod fetch;
+#[cfg(feature = "cli")]
+pub mod fontpack;
+pub mod fonts;
 pub mod frontmatter;
 pub mod math;
+pub mod package;
+#[cfg(feature = "cli")]
+pub mod preview;
 pub mod template;
 
 #[cfg(feature = "cli")]
 pub mod watch;
 
-pub use compile::{CompileError, compile_typst_to_pdf};
-pub use convert::{ConvertError, ConvertOptions, ConvertedDocument, convert_markdown_to_typst};
+pub use compile::{
+    CompileError, CompileOptions, Compiled, IncrementalCompiler, OutputFormat, PdfStandard,
+    compile_typst_to_bytes, compile_typst_to_files, compile_typst_to_format, compile_typst_to_pdf,
+    compile_typst_to_pdf_with_options, evict_memoized, export_typst_project,
+};
+pub use convert::{
+    Asset, AssetSource, ConvertError, ConvertOptions, ConvertWarning, ConvertedDocument,
+    DocumentMetadata, convert_markdown_to_typst,
+};
+pub use diagnostic::{Diagnostic, Severity, SourceMap, SourcePosition, Stage};
+pub use fonts::{FontOptions, FontSet};
 pub use frontmatter::{FrontMatter, ParsedMarkdown, split_frontmatter};
-pub use template::{Style, compose_document};
+pub use template::{CustomTemplate, FontFallbacks, Style, compose_document};
 
 /// High-level options for the one-shot `markdown_to_pdf` function.
 #[derive(Debug, Clone)]
@@ -70,8 +88,21 @@ pub struct Options {
     pub lang: Option<String>,
     /// Force table of contents on/off. `None` = use frontmatter / inline `[toc]`.
     pub toc: Option<bool>,
-    /// Custom Typst template source. When set, overrides the built-in style.
-    pub custom_template: Option<String>,
+    /// Custom Typst template, from a source string or
+    /// [`CustomTemplate::load`]. When set, overrides the built-in style.
+    pub custom_template: Option<CustomTemplate>,
+    /// Directory relative image paths are resolved against. `None` = current directory.
+    pub base_dir: Option<std::path::PathBuf>,
+    /// Only use cached remote images; uncached ones become placeholders.
+    /// Typst packages are not downloaded either.
+    pub offline: bool,
+    /// PDF standard to conform to. Default: plain PDF 1.7.
+    pub pdf_standard: PdfStandard,
+    /// Pin the current time (seconds since the Unix epoch) for reproducible
+    /// output. `None` = `SOURCE_DATE_EPOCH` if set, otherwise the system clock.
+    pub timestamp: Option<i64>,
+    /// Extra font paths and whether to use system fonts.
+    pub fonts: FontOptions,
 }
 
 impl Default for Options {
@@ -83,15 +114,30 @@ impl Default for Options {
             lang: None,
             toc: None,
             custom_template: None,
+            base_dir: None,
+            offline: false,
+            pdf_standard: PdfStandard::default(),
+            timestamp: None,
+            fonts: FontOptions::default(),
         }
     }
 }
 
-/// Convert a Markdown string to PDF bytes in one call.
+/// Convert a Markdown string to PDF bytes in one call. Warnings are
+/// discarded; use [`markdown_to_pdf_with_warnings`] to receive them.
 ///
 /// This is the highest-level API. For streaming / watch / incremental use
 /// cases, use the lower-level modules directly.
 pub fn markdown_to_pdf(markdown: &str, options: &Options) -> Result<Vec<u8>, Error> {
+    markdown_to_pdf_with_warnings(markdown, options).map(|compiled| compiled.output)
+}
+
+/// Like [`markdown_to_pdf`], also returning the frontmatter, conversion and
+/// compilation warnings in pipeline order.
+pub fn markdown_to_pdf_with_warnings(
+    markdown: &str,
+    options: &Options,
+) -> Result<Compiled<Vec<u8>>, Error> {
     let parsed = split_frontmatter(markdown).map_err(Error::Frontmatter)?;
 
     let converted = convert_markdown_to_typst(
@@ -102,36 +148,64 @@ pub fn markdown_to_pdf(markdown: &str, options: &Options) -> Result<Vec<u8>, Err
             author_override: options.author.clone(),
             lang_override: options.lang.clone(),
             force_toc: options.toc,
+            base_dir: options.base_dir.clone(),
+            line_offset: parsed.body_line_offset,
         },
     )
     .map_err(Error::Convert)?;
 
     let typst_source = if let Some(ref custom) = options.custom_template {
-        template::compose_document_with_custom(
-            custom,
+        template::compose_document_with_custom_metadata(
+            &custom.prelude(),
             converted.title.as_deref(),
             &converted.authors,
             &converted.lang,
             converted.toc,
             &converted.body,
+            &converted.metadata,
         )
     } else {
-        compose_document(
+        template::compose_document_with_metadata(
             options.style,
             converted.title.as_deref(),
             &converted.authors,
             &converted.lang,
             converted.toc,
             &converted.body,
+            &converted.metadata,
         )
     };
 
-    // Compile to PDF in memory (write to temp, read back)
-    let tmp = std::env::temp_dir().join(format!("mdxport_{}.pdf", std::process::id()));
-    let pdf_bytes = compile_typst_to_pdf(&typst_source, &tmp).map_err(Error::Compile)?;
-    let _ = std::fs::remove_file(&tmp);
-
-    Ok(pdf_bytes)
+    let compile_options =
+        CompileOptions {
+            assets: converted.assets,
+            offline: options.offline,
+            pdf_standard: options.pdf_standard,
+            timestamp: options.timestamp,
+            fonts: Some(FontSet::shared(
+                &options.fonts.clone().with_template_fonts(
+                    options
+                        .custom_template
+                        .as_ref()
+                        .and_then(CustomTemplate::mount_dir),
+                ),
+            )),
+            template_dir: options
+                .custom_template
+                .as_ref()
+                .and_then(|custom| custom.mount_dir().map(std::path::Path::to_path_buf)),
+            source_map: Some(converted.source_map.shifted(
+                template::body_offset(&typst_source, &converted.body).unwrap_or_default(),
+            )),
+            ..CompileOptions::default()
+        };
+    let mut compiled =
+        compile_typst_to_bytes(&typst_source, &compile_options).map_err(Error::Compile)?;
+    let mut warnings = parsed.warnings;
+    warnings.extend(converted.warnings.iter().map(Diagnostic::from));
+    warnings.append(&mut compiled.warnings);
+    compiled.warnings = warnings;
+    Ok(compiled)
 }
 
 /// Top-level error type combining all pipeline stages.
@@ -152,6 +226,23 @@ impl std::fmt::Display for Error {
     }
 }
 
+impl Error {
+    /// The structured diagnostics behind this error, tagged with the stage
+    /// that raised them.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            Self::Frontmatter(e) => vec![Diagnostic::from(e)],
+            Self::Convert(e) => vec![Diagnostic::from(e)],
+            Self::Compile(CompileError::Typst(diagnostics)) => diagnostics.clone(),
+            Self::Compile(e @ CompileError::Io(_)) => vec![Diagnostic::new(
+                Stage::Compile,
+                Severity::Error,
+                e.to_string(),
+            )],
+        }
+    }
+}
+
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
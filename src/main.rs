@@ -1,16 +1,35 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use clap::{Args, Parser, Subcommand};
 use mdxport::{
-    compile::compile_typst_to_pdf,
-    convert::{ConvertOptions, convert_markdown_to_typst},
-    frontmatter::{ParsedMarkdown, split_frontmatter},
-    template::{Style, compose_document},
+    compile::{
+        CompileError, CompileOptions, Compiled, DEFAULT_PPI, OutputFormat, PdfStandard,
+        compile_typst_to_format, document_ident, export_typst_project, page_output_paths,
+    },
+    convert::{ConvertOptions, convert_markdown_to_typst, document_text},
+    diagnostic::{Diagnostic, Severity},
+    fontpack::{
+        self, DEFAULT_PACK, FONT_PACKS, FileStatus, FontPack, Installed, PackSource, find_pack,
+    },
+    fonts::{FontOptions, FontSet, Resolution, check_coverage},
+    frontmatter::{DocumentDate, ParsedMarkdown, split_frontmatter},
+    preview::preview,
+    template::{
+        CustomTemplate, FontFallbacks, Style, body_offset, compose_document_with_custom_metadata,
+        compose_document_with_metadata,
+    },
     watch::{WatchCommand, watch_inputs},
 };
+use serde::Serialize;
+use typst::text::{FontStyle, FontVariant};
 
 mod update;
 
@@ -29,10 +48,28 @@ struct Cli {
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    Convert(ConvertArgs),
+    Convert(Box<ConvertArgs>),
+    /// Serve live-reloading SVG pages of a document on localhost.
+    Preview(Box<PreviewArgs>),
     Fonts(FontsArgs),
 }
 
+#[derive(Debug, Args, Clone)]
+struct PreviewArgs {
+    #[arg(long, default_value = "127.0.0.1", help = "Address to listen on.")]
+    host: String,
+
+    #[arg(
+        long,
+        default_value_t = 3030,
+        help = "Port to listen on (0 picks a free port)."
+    )]
+    port: u16,
+
+    #[command(flatten)]
+    convert: ConvertArgs,
+}
+
 #[derive(Debug, Args, Clone)]
 struct ConvertArgs {
     #[arg(help = "Input markdown files. If omitted, read from stdin.")]
@@ -41,19 +78,67 @@ struct ConvertArgs {
     #[arg(
         short,
         long,
-        help = "Output path. Defaults to <input>.pdf for file input."
+        help = "Output path. Defaults to <input>.pdf for file input. Use - to write to stdout."
     )]
     output: Option<PathBuf>,
 
+    #[arg(
+        long,
+        value_name = "format",
+        value_parser = clap::builder::PossibleValuesParser::new(["pdf", "png", "svg"]),
+        help = "Output format. Inferred from the -o extension, otherwise pdf. PNG/SVG write one file per page."
+    )]
+    format: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "ppi",
+        default_value_t = DEFAULT_PPI,
+        help = "Pixels per inch for PNG output."
+    )]
+    ppi: f32,
+
+    #[arg(
+        long = "pdf-standard",
+        value_name = "standard",
+        default_value = "1.7",
+        value_parser = clap::builder::PossibleValuesParser::new(["1.7", "a-2b", "a-3b"]),
+        help = "PDF standard to conform to. Use a-2b for PDF/A-2b archival output."
+    )]
+    pdf_standard: String,
+
+    #[arg(
+        long,
+        value_name = "date",
+        help = "Pin the build date (YYYY-MM-DD or Unix seconds) for reproducible output. Defaults to SOURCE_DATE_EPOCH, then the current time."
+    )]
+    date: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "kind",
+        value_parser = clap::builder::PossibleValuesParser::new(["typst"]),
+        conflicts_with_all = ["format", "watch"],
+        help = "Write the composed Typst project (main.typ plus assets) to a directory instead of compiling. Defaults to <input>-typst/; an -o file name such as out.pdf becomes out-typst/."
+    )]
+    emit: Option<String>,
+
     #[arg(short, long, default_value = "modern-tech", value_name = "style", value_parser = clap::builder::PossibleValuesParser::new(["modern-tech", "classic-editorial"]))]
     style: String,
 
     #[arg(
         long = "template",
-        help = "Path to a custom Typst template file (.typ)."
+        help = "Path to a custom Typst template file (.typ), or a template directory with a template.typ or main.typ entry file."
     )]
     custom_template: Option<PathBuf>,
 
+    #[arg(
+        long = "base-dir",
+        value_name = "DIR",
+        help = "Directory to resolve relative image paths against. Defaults to the input file's directory (or the current directory for stdin)."
+    )]
+    base_dir: Option<PathBuf>,
+
     #[arg(
         short = 't',
         long,
@@ -77,9 +162,60 @@ struct ConvertArgs {
     #[arg(long = "no-toc", help = "Disable table of contents.")]
     no_toc: bool,
 
-    #[arg(short, long, help = "Watch input files and recompile on change.")]
+    #[arg(
+        long,
+        help = "Use only cached remote images and installed Typst packages; substitute placeholders for uncached images."
+    )]
+    offline: bool,
+
+    #[command(flatten)]
+    fonts: FontArgs,
+
+    #[arg(
+        long = "deny-warnings",
+        help = "Fail (exit 1, no output written) if conversion or Typst compilation reports any warning."
+    )]
+    deny_warnings: bool,
+
+    #[arg(
+        long = "keep-going",
+        help = "Convert every input even if some fail, then print a summary. Exits 3 if some inputs failed, 1 if all did."
+    )]
+    keep_going: bool,
+
+    #[arg(
+        short,
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        help = "Convert up to N inputs in parallel; 0 uses every CPU. Logs stay in input order."
+    )]
+    jobs: usize,
+
+    #[arg(
+        long = "message-format",
+        value_name = "format",
+        default_value = "human",
+        value_parser = clap::builder::PossibleValuesParser::new(["human", "json"]),
+        conflicts_with = "watch",
+        help = "How to report diagnostics. json prints one object per line on stdout: each diagnostic, then a summary."
+    )]
+    message_format: String,
+
+    #[arg(
+        short,
+        long,
+        help = "Watch inputs and recompile on change. Directory inputs are watched recursively; outputs mirror their layout under -o."
+    )]
     watch: bool,
 
+    #[arg(
+        long = "keep-outputs",
+        requires = "watch",
+        help = "In watch mode, keep the output of a Markdown file deleted from a watched directory."
+    )]
+    keep_outputs: bool,
+
     #[arg(short, long, default_value_t = false, help = "Verbose diagnostics.")]
     verbose: bool,
 
@@ -91,6 +227,38 @@ struct ConvertArgs {
     quiet: bool,
 }
 
+#[derive(Debug, Args, Clone)]
+struct FontArgs {
+    #[arg(
+        long = "font-path",
+        value_name = "PATH",
+        help = "Extra font file or directory; repeatable. Also read from MDXPORT_FONT_PATHS. A fonts/ directory next to the input is used automatically."
+    )]
+    font_paths: Vec<PathBuf>,
+
+    #[arg(
+        long = "no-system-fonts",
+        help = "Ignore system fonts and ~/.mdxport/fonts; use only bundled fonts and font paths."
+    )]
+    no_system_fonts: bool,
+}
+
+impl FontArgs {
+    /// Font options from the command line, with `MDXPORT_FONT_PATHS` (a
+    /// `PATH`-style list) appended to the `--font-path` flags.
+    fn options(self) -> FontOptions {
+        let mut font_paths = self.font_paths;
+        if let Some(paths) = std::env::var_os("MDXPORT_FONT_PATHS") {
+            font_paths
+                .extend(std::env::split_paths(&paths).filter(|path| !path.as_os_str().is_empty()));
+        }
+        FontOptions {
+            font_paths,
+            system_fonts: !self.no_system_fonts,
+        }
+    }
+}
+
 #[derive(Debug, Args)]
 struct FontsArgs {
     #[command(subcommand)]
@@ -99,29 +267,71 @@ struct FontsArgs {
 
 #[derive(Debug, Subcommand)]
 enum FontsCommand {
-    Install,
-    List,
-}
-
-const CJK_FONT_WARNING: &str = "Warning: CJK characters detected but no CJK fonts found. Run mdxport fonts install to download Noto CJK fonts (~60MB).";
-const FONT_DOWNLOADS: [(&str, &str); 4] = [
-    (
-        "NotoSansCJKsc-Regular.otf",
-        "https://github.com/notofonts/noto-cjk/raw/main/Sans/OTF/SimplifiedChinese/NotoSansCJKsc-Regular.otf",
-    ),
-    (
-        "NotoSansCJKsc-Bold.otf",
-        "https://github.com/notofonts/noto-cjk/raw/main/Sans/OTF/SimplifiedChinese/NotoSansCJKsc-Bold.otf",
-    ),
-    (
-        "NotoSerifCJKsc-Regular.otf",
-        "https://github.com/notofonts/noto-cjk/raw/main/Serif/OTF/SimplifiedChinese/NotoSerifCJKsc-Regular.otf",
-    ),
-    (
-        "NotoSerifCJKsc-Bold.otf",
-        "https://github.com/notofonts/noto-cjk/raw/main/Serif/OTF/SimplifiedChinese/NotoSerifCJKsc-Bold.otf",
-    ),
-];
+    /// Download font packs into ~/.mdxport/fonts.
+    Install(FontsInstallArgs),
+    /// Delete installed font packs.
+    Remove(FontsRemoveArgs),
+    /// Check installed pack files against the checksums recorded at install.
+    Verify,
+    /// List every font family documents can use, with styles and files.
+    List(FontArgs),
+    /// Report characters of a Markdown file that no available font covers.
+    Check(FontsCheckArgs),
+}
+
+#[derive(Debug, Args)]
+struct FontsInstallArgs {
+    #[arg(
+        value_name = "PACK",
+        value_parser = clap::builder::PossibleValuesParser::new(FONT_PACKS.iter().map(|pack| pack.name)),
+        help = "Font packs to install. Default: sc."
+    )]
+    packs: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with = "from",
+        help = "Download pack files from this base URL instead of upstream (also MDXPORT_FONT_MIRROR). A SHA256SUMS file there is checked."
+    )]
+    mirror: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Install from a directory or zip archive instead of downloading, e.g. on air-gapped machines. A SHA256SUMS file at its top level is checked."
+    )]
+    from: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct FontsRemoveArgs {
+    #[arg(
+        value_name = "PACK",
+        required = true,
+        value_parser = clap::builder::PossibleValuesParser::new(FONT_PACKS.iter().map(|pack| pack.name)),
+        help = "Font packs to remove."
+    )]
+    packs: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct FontsCheckArgs {
+    #[arg(help = "Markdown file to check.")]
+    input: PathBuf,
+
+    #[arg(short, long, default_value = "modern-tech", value_name = "style", value_parser = clap::builder::PossibleValuesParser::new(["modern-tech", "classic-editorial"]))]
+    style: String,
+
+    #[arg(
+        long = "template",
+        help = "Custom Typst template whose font lists to check against."
+    )]
+    custom_template: Option<PathBuf>,
+
+    #[command(flatten)]
+    fonts: FontArgs,
+}
 
 #[derive(Debug)]
 enum InputSource {
@@ -137,9 +347,17 @@ struct ProcessOptions<'a> {
     force_toc: Option<bool>,
     verbose: bool,
     style: Style,
-    custom_template: Option<String>,
+    custom_template: Option<CustomTemplate>,
+    base_dir: &'a Option<PathBuf>,
+    offline: bool,
+    format: OutputFormat,
+    ppi: f32,
+    pdf_standard: PdfStandard,
+    timestamp: Option<i64>,
+    emit_typst: bool,
+    deny_warnings: bool,
     multiple_inputs: bool,
-    has_user_fonts: bool,
+    fonts: FontOptions,
 }
 
 fn main() {
@@ -152,50 +370,152 @@ fn main() {
     }
 
     if let Err(error) = run(cli) {
-        eprintln!("[mdxport] {error}");
-        process::exit(1);
+        eprintln!("[mdxport] {}", error.message);
+        process::exit(error.code);
+    }
+}
+
+/// Exit status for conversion failures, including a single failed input.
+const EXIT_FAILURE: i32 = 1;
+/// Exit status for invalid arguments, matching clap's own usage errors.
+const EXIT_USAGE: i32 = 2;
+/// Exit status when `--keep-going` converted some inputs but not all.
+const EXIT_PARTIAL: i32 = 3;
+
+#[derive(Debug)]
+struct RunError {
+    code: i32,
+    message: String,
+}
+
+impl RunError {
+    fn usage(message: impl Into<String>) -> Self {
+        Self {
+            code: EXIT_USAGE,
+            message: message.into(),
+        }
     }
 }
 
-fn run(cli: Cli) -> Result<(), String> {
+impl From<String> for RunError {
+    fn from(message: String) -> Self {
+        Self {
+            code: EXIT_FAILURE,
+            message,
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), RunError> {
     let Cli { command, convert } = cli;
     match command {
-        Some(Command::Fonts(fonts)) => run_fonts(fonts),
-        Some(Command::Convert(convert)) => run_convert(convert),
-        None => run_convert(convert),
+        Some(Command::Fonts(fonts)) => Ok(run_fonts(fonts)?),
+        Some(Command::Convert(convert)) => run_convert(*convert, None),
+        Some(Command::Preview(preview)) => {
+            let PreviewArgs {
+                host,
+                port,
+                convert,
+            } = *preview;
+            run_convert(convert, Some(format!("{host}:{port}")))
+        }
+        None => run_convert(convert, None),
     }
 }
 
-fn run_convert(cli: ConvertArgs) -> Result<(), String> {
+/// Convert (or watch) the inputs; with `preview_addr`, serve the single
+/// input as a live preview on that address instead of writing files.
+fn run_convert(cli: ConvertArgs, preview_addr: Option<String>) -> Result<(), RunError> {
     let ConvertArgs {
         inputs,
         output,
+        format,
+        ppi,
+        pdf_standard,
+        date,
+        emit,
         style,
         custom_template,
+        base_dir,
         title,
         author,
         lang,
         toc,
         no_toc,
+        offline,
+        fonts,
+        deny_warnings,
+        keep_going,
+        jobs,
+        message_format,
         watch,
+        keep_outputs,
         verbose,
         quiet,
     } = cli;
+    let json = message_format == "json";
 
     if inputs.is_empty() && watch {
-        return Err("watch mode requires at least one input file".to_string());
+        return Err(RunError::usage(
+            "watch mode requires at least one input file",
+        ));
     }
 
-    let multiple_inputs = inputs.len() > 1;
+    if !watch && let Some(dir) = inputs.iter().find(|input| input.is_dir()) {
+        return Err(RunError::usage(format!(
+            "{} is a directory; directory inputs require --watch",
+            dir.display()
+        )));
+    }
+    // A watched directory is a batch of its own
+    let multiple_inputs = inputs.len() > 1 || inputs.iter().any(|input| input.is_dir());
+    if output.as_deref().is_some_and(is_stdout) {
+        if multiple_inputs {
+            return Err(RunError::usage("-o - accepts a single input"));
+        }
+        if watch || emit.is_some() {
+            return Err(RunError::usage(
+                "-o - cannot be combined with --watch or --emit",
+            ));
+        }
+        if json {
+            return Err(RunError::usage(
+                "-o - cannot be combined with --message-format json",
+            ));
+        }
+    }
     if multiple_inputs
         && let Some(output) = &output
         && output.extension().is_some()
     {
-        return Err("multiple input files require output directory path".to_string());
+        return Err(RunError::usage(
+            "multiple input files require output directory path",
+        ));
     }
 
-    let style = Style::try_from(style.as_str()).map_err(|e| e.to_string())?;
+    let style = Style::try_from(style.as_str()).map_err(|e| RunError::usage(e.to_string()))?;
+    let format = match format {
+        Some(format) => {
+            OutputFormat::try_from(format.as_str()).map_err(|e| RunError::usage(e.to_string()))?
+        }
+        None => output
+            .as_deref()
+            .filter(|_| !multiple_inputs)
+            .and_then(OutputFormat::from_path)
+            .unwrap_or_default(),
+    };
+    let pdf_standard =
+        PdfStandard::try_from(pdf_standard.as_str()).map_err(|e| RunError::usage(e.to_string()))?;
+    let timestamp = date
+        .as_deref()
+        .map(parse_date_flag)
+        .transpose()
+        .map_err(RunError::usage)?;
+    if !(ppi.is_finite() && ppi > 0.0) {
+        return Err(RunError::usage(format!("invalid --ppi value: {ppi}")));
+    }
     let force_toc = resolve_force_toc(no_toc, toc);
+    let fonts = fonts.options();
 
     let input_sources = if inputs.is_empty() {
         vec![InputSource::Stdin(read_stdin()?)]
@@ -206,6 +526,38 @@ fn run_convert(cli: ConvertArgs) -> Result<(), String> {
             .collect::<Vec<_>>()
     };
 
+    if let Some(addr) = preview_addr {
+        let [InputSource::File(path)] = input_sources.as_slice() else {
+            return Err(RunError::usage("preview takes exactly one input file"));
+        };
+        if output.is_some() || watch {
+            return Err(RunError::usage("preview writes no files; drop -o and -w"));
+        }
+        let command = WatchCommand {
+            style,
+            custom_template,
+            output: None,
+            multiple_inputs: false,
+            title_override: title,
+            author_override: author,
+            lang_override: lang,
+            force_toc,
+            base_dir,
+            offline,
+            fonts,
+            format: OutputFormat::Svg,
+            ppi,
+            pdf_standard,
+            timestamp,
+            deny_warnings,
+            keep_outputs,
+            verbose,
+        };
+        return Ok(
+            preview(path.clone(), &command, &addr).map_err(|e| format!("preview failed: {e}"))?
+        );
+    }
+
     if watch {
         let files = input_sources
             .iter()
@@ -216,25 +568,37 @@ fn run_convert(cli: ConvertArgs) -> Result<(), String> {
             .collect::<Vec<_>>();
 
         if files.is_empty() {
-            return Err("watch mode requires file inputs (stdin cannot be watched)".to_string());
+            return Err(RunError::usage(
+                "watch mode requires file inputs (stdin cannot be watched)",
+            ));
         }
 
         let command = WatchCommand {
             style,
+            custom_template: custom_template.clone(),
             output: output.clone(),
             multiple_inputs,
             title_override: title.clone(),
             author_override: author.clone(),
             lang_override: lang.clone(),
             force_toc,
+            base_dir: base_dir.clone(),
+            offline,
+            fonts,
+            format,
+            ppi,
+            pdf_standard,
+            timestamp,
+            deny_warnings,
+            keep_outputs,
             verbose,
         };
 
-        return watch_inputs(&files, &command).map_err(|e| format!("watch failed: {e}"));
+        return Ok(watch_inputs(&files, &command).map_err(|e| format!("watch failed: {e}"))?);
     }
 
     let custom_template = custom_template
-        .map(|p| fs::read_to_string(&p).map_err(|e| format!("read template: {e}")))
+        .map(|p| CustomTemplate::load(&p).map_err(|e| format!("read template: {e}")))
         .transpose()?;
 
     let process_options = ProcessOptions {
@@ -246,14 +610,55 @@ fn run_convert(cli: ConvertArgs) -> Result<(), String> {
         verbose,
         style,
         custom_template,
+        base_dir: &base_dir,
+        offline,
+        format,
+        ppi,
+        pdf_standard,
+        timestamp,
+        emit_typst: emit.is_some(),
+        deny_warnings,
         multiple_inputs,
-        has_user_fonts: user_font_dir_has_font_files(),
+        fonts,
     };
 
-    let mut warned_about_missing_fonts = false;
-    input_sources.iter().try_for_each(|input| {
-        process_one(input, &process_options, &mut warned_about_missing_fonts)
-    })?;
+    let jobs = match jobs {
+        0 => thread::available_parallelism().map_or(1, usize::from),
+        jobs => jobs,
+    };
+    let reports = convert_all(&input_sources, &process_options, jobs, keep_going, json);
+    if json {
+        print_summary(&reports);
+    } else if keep_going && reports.len() > 1 {
+        print_summary_table(&reports);
+    }
+
+    let failed = reports
+        .iter()
+        .filter(|report| report.error.is_some())
+        .count();
+    if failed > 0 {
+        if !keep_going || reports.len() == 1 {
+            let report = reports.iter().find(|report| report.error.is_some());
+            return Err(report
+                .map(|report| {
+                    format!(
+                        "{}: {}",
+                        report.input,
+                        report.error.as_deref().unwrap_or_default()
+                    )
+                })
+                .unwrap_or_default()
+                .into());
+        }
+        if failed == reports.len() {
+            return Err(format!("all {failed} inputs failed").into());
+        }
+        return Err(RunError {
+            code: EXIT_PARTIAL,
+            message: format!("{failed} of {} inputs failed", reports.len()),
+        });
+    }
 
     if !quiet {
         update::check_for_updates();
@@ -264,15 +669,53 @@ fn run_convert(cli: ConvertArgs) -> Result<(), String> {
 
 fn run_fonts(fonts: FontsArgs) -> Result<(), String> {
     match fonts.command {
-        FontsCommand::Install => install_fonts(),
-        FontsCommand::List => list_fonts(),
+        FontsCommand::Install(args) => install_fonts(args),
+        FontsCommand::Remove(args) => remove_fonts(args),
+        FontsCommand::Verify => verify_fonts(),
+        FontsCommand::List(args) => list_fonts(args),
+        FontsCommand::Check(args) => check_fonts(args),
     }
 }
 
 fn process_one(
     input: &InputSource,
     options: &ProcessOptions<'_>,
-    warned_about_missing_fonts: &mut bool,
+    warned_about_missing_fonts: &AtomicBool,
+) -> FileReport {
+    let started = Instant::now();
+    let path_hint = match input {
+        InputSource::File(path) => Some(path.as_path()),
+        InputSource::Stdin(_) => None,
+    };
+    let mut report = FileReport {
+        input: path_hint.map_or_else(|| "<stdin>".to_string(), |p| p.display().to_string()),
+        markdown: String::new(),
+        diagnostics: Vec::new(),
+        outputs: Vec::new(),
+        pages: 0,
+        duration: Duration::ZERO,
+        error: None,
+    };
+
+    if let Err(error) = convert_one(input, options, warned_about_missing_fonts, &mut report) {
+        report.error = Some(error);
+    }
+    if let Some(path) = path_hint {
+        for diagnostic in &mut report.diagnostics {
+            diagnostic.file.get_or_insert_with(|| path.to_path_buf());
+        }
+    }
+    report.duration = started.elapsed();
+    report
+}
+
+/// Convert `input`, recording diagnostics and written files in `report`.
+/// The error is a one-line summary; details are in `report.diagnostics`.
+fn convert_one(
+    input: &InputSource,
+    options: &ProcessOptions<'_>,
+    warned_about_missing_fonts: &AtomicBool,
+    report: &mut FileReport,
 ) -> Result<(), String> {
     let path_hint = match input {
         InputSource::File(path) => Some(path.as_path()),
@@ -285,11 +728,40 @@ fn process_one(
         }
         InputSource::Stdin(markdown) => markdown.clone(),
     };
+    report.markdown.clone_from(&source);
+
+    let template_dir = options
+        .custom_template
+        .as_ref()
+        .and_then(CustomTemplate::mount_dir);
+    let fonts = options
+        .fonts
+        .clone()
+        .with_project_fonts(
+            path_hint
+                .and_then(Path::parent)
+                .or(options.base_dir.as_deref())
+                .unwrap_or(Path::new("")),
+        )
+        .with_template_fonts(template_dir);
+    let fonts = FontSet::shared(&fonts);
+    maybe_warn_missing_cjk_fonts(&source, &fonts, warned_about_missing_fonts);
+
+    let ParsedMarkdown {
+        frontmatter,
+        body,
+        body_line_offset,
+        warnings,
+    } = split_frontmatter(&source).map_err(|e| {
+        report.diagnostics.push(Diagnostic::from(&e));
+        "frontmatter parse failed".to_string()
+    })?;
+    report.diagnostics.extend(warnings);
 
-    maybe_warn_missing_cjk_fonts(&source, options.has_user_fonts, warned_about_missing_fonts);
-
-    let ParsedMarkdown { frontmatter, body } =
-        split_frontmatter(&source).map_err(|e| format!("frontmatter parse: {e}"))?;
+    let base_dir = options
+        .base_dir
+        .clone()
+        .or_else(|| path_hint.and_then(Path::parent).map(Path::to_path_buf));
 
     let conversion = convert_markdown_to_typst(
         &body,
@@ -299,37 +771,129 @@ fn process_one(
             author_override: options.author.clone(),
             lang_override: options.lang.clone(),
             force_toc: options.force_toc,
+            base_dir,
+            line_offset: body_line_offset,
         },
     )
-    .map_err(|e| format!("markdown conversion failed: {e}"))?;
+    .map_err(|e| {
+        report.diagnostics.push(Diagnostic::from(&e));
+        "markdown conversion failed".to_string()
+    })?;
+    report
+        .diagnostics
+        .extend(conversion.warnings.iter().map(Diagnostic::from));
 
     let typst_source = if let Some(ref tmpl) = options.custom_template {
-        mdxport::template::compose_document_with_custom(
-            tmpl,
+        compose_document_with_custom_metadata(
+            &tmpl.prelude(),
             conversion.title.as_deref(),
             &conversion.authors,
             &conversion.lang,
             conversion.toc,
             &conversion.body,
+            &conversion.metadata,
         )
     } else {
-        compose_document(
+        compose_document_with_metadata(
             options.style,
             conversion.title.as_deref(),
             &conversion.authors,
             &conversion.lang,
             conversion.toc,
             &conversion.body,
+            &conversion.metadata,
         )
     };
 
+    let compile_options = CompileOptions {
+        assets: conversion.assets,
+        offline: options.offline,
+        format: options.format,
+        ppi: Some(options.ppi),
+        ident: path_hint.map(document_ident),
+        pdf_standard: options.pdf_standard,
+        timestamp: options.timestamp,
+        source_map: Some(
+            conversion
+                .source_map
+                .shifted(body_offset(&typst_source, &conversion.body).unwrap_or_default()),
+        ),
+        source_path: path_hint.map(Path::to_path_buf),
+        fonts: Some(fonts),
+        template_dir: template_dir.map(Path::to_path_buf),
+        ..CompileOptions::default()
+    };
+
+    if options.emit_typst {
+        let out_dir = match (options.output, path_hint) {
+            (Some(path), Some(path_hint)) if options.multiple_inputs => {
+                path.join(path_hint.file_stem().unwrap_or_default())
+            }
+            // A file name like `out.pdf` names the project, not its directory
+            (Some(path), _) if path.extension().is_some() && !path.is_dir() => {
+                typst_project_dir(path)
+            }
+            (Some(path), _) => path.clone(),
+            (None, Some(path)) => typst_project_dir(path),
+            (None, None) => PathBuf::from("output-typst"),
+        };
+        let written = export_typst_project(&typst_source, &compile_options, &out_dir)
+            .map_err(|e| format!("export failed: {e}"))?;
+
+        for path in written {
+            let bytes = fs::metadata(&path).map_or(0, |metadata| metadata.len() as usize);
+            report.outputs.push(OutputFile { path, bytes });
+        }
+        return deny_warnings(options, report);
+    }
+
+    let Compiled {
+        output: mut pages,
+        warnings,
+        pages: page_count,
+    } = compile_typst_to_format(&typst_source, &compile_options).map_err(|e| match e {
+        CompileError::Typst(diagnostics) => {
+            let count = diagnostics.len();
+            report.diagnostics.extend(diagnostics);
+            format!("compile failed with {count} error(s)")
+        }
+        e => format!("compile failed: {e}"),
+    })?;
+    report.diagnostics.extend(warnings);
+    // Checked before writing so a denied document leaves no output behind
+    deny_warnings(options, report)?;
+
+    if let Some(output) = options.output
+        && is_stdout(output)
+    {
+        if pages.len() != 1 {
+            return Err(format!(
+                "cannot write {} pages to stdout; use -o <file> instead",
+                pages.len()
+            ));
+        }
+        let bytes = pages.remove(0);
+        report.pages = page_count;
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(&bytes)
+            .and_then(|()| stdout.flush())
+            .map_err(|e| format!("write stdout: {e}"))?;
+        report.outputs.push(OutputFile {
+            path: PathBuf::from("-"),
+            bytes: bytes.len(),
+        });
+        return Ok(());
+    }
+
+    let extension = options.format.extension();
     let out_path = match (options.output, path_hint) {
         (Some(path), Some(path_hint)) if options.multiple_inputs => path
             .join(path_hint.file_name().unwrap_or_default())
-            .with_extension("pdf"),
+            .with_extension(extension),
         (Some(path), _) => path.clone(),
-        (None, Some(path)) => path.with_extension("pdf"),
-        (None, None) => PathBuf::from("output.pdf"),
+        (None, Some(path)) => path.with_extension(extension),
+        (None, None) => PathBuf::from("output").with_extension(extension),
     };
 
     if let Some(parent) = out_path.parent()
@@ -338,16 +902,223 @@ fn process_one(
         fs::create_dir_all(parent).map_err(|e| format!("create output dir: {e}"))?;
     }
 
-    let pdf = compile_typst_to_pdf(&typst_source, &out_path)
-        .map_err(|e| format!("compile failed: {e}"))?;
+    report.pages = page_count;
+    for (path, bytes) in page_output_paths(&out_path, pages.len())
+        .into_iter()
+        .zip(pages)
+    {
+        fs::write(&path, &bytes).map_err(|e| format!("write {}: {e}", path.display()))?;
+        report.outputs.push(OutputFile {
+            path,
+            bytes: bytes.len(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Convert `inputs` on up to `jobs` worker threads sharing the global font
+/// storage. Each report is printed once every earlier input is done, so logs
+/// come out in input order regardless of `jobs`. Without `keep_going`, no
+/// input starts after a failure, but inputs other workers had already
+/// started still finish and are reported, since their outputs are written.
+fn convert_all(
+    inputs: &[InputSource],
+    options: &ProcessOptions<'_>,
+    jobs: usize,
+    keep_going: bool,
+    json: bool,
+) -> Vec<FileReport> {
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let warned_about_missing_fonts = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, inputs.len().max(1)) {
+            let sender = sender.clone();
+            let (next, stop, warned) = (&next, &stop, &warned_about_missing_fonts);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(input) = inputs.get(index) else {
+                        break;
+                    };
+                    let report = process_one(input, options, warned);
+                    if report.error.is_some() && !keep_going {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                    if sender.send((index, report)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut reports: Vec<FileReport> = Vec::with_capacity(inputs.len());
+        for (index, report) in receiver {
+            pending.insert(index, report);
+            while let Some(report) = pending.remove(&reports.len()) {
+                // JSON output owns stdout; the summary lists what was written
+                report.print(json, options.verbose && !json);
+                reports.push(report);
+            }
+        }
+        reports
+    })
+}
+
+/// Outcome of converting one input.
+struct FileReport {
+    /// Input path, or `<stdin>`.
+    input: String,
+    markdown: String,
+    diagnostics: Vec<Diagnostic>,
+    outputs: Vec<OutputFile>,
+    pages: usize,
+    duration: Duration,
+    /// Why the input failed; details are in `diagnostics`.
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OutputFile {
+    path: PathBuf,
+    bytes: usize,
+}
+
+impl FileReport {
+    fn count(&self, severity: Severity) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == severity)
+            .count()
+    }
+
+    /// Print diagnostics, then (with `verbose`) the files written. Workers
+    /// only record these, so the batch prints them in input order.
+    fn print(&self, json: bool, verbose: bool) {
+        for diagnostic in &self.diagnostics {
+            if json {
+                let mut value = serde_json::json!({ "type": "diagnostic" });
+                if let (Some(object), Ok(serde_json::Value::Object(fields))) =
+                    (value.as_object_mut(), serde_json::to_value(diagnostic))
+                {
+                    object.extend(fields);
+                }
+                println!("{value}");
+            } else {
+                eprint!("{}", diagnostic.render(&self.input, &self.markdown));
+            }
+        }
+        if verbose {
+            for output in &self.outputs {
+                if is_stdout(&output.path) {
+                    eprintln!("written <stdout> ({} bytes)", output.bytes);
+                } else {
+                    println!("written {} ({} bytes)", output.path.display(), output.bytes);
+                }
+            }
+        }
+    }
+}
 
-    if options.verbose {
-        println!("written {} ({} bytes)", out_path.display(), pdf.len());
+/// Per-input outcome table printed at the end of a `--keep-going` batch.
+fn print_summary_table(reports: &[FileReport]) {
+    let width = reports
+        .iter()
+        .map(|report| report.input.chars().count())
+        .max()
+        .unwrap_or_default();
+    eprintln!("[mdxport] summary:");
+    for report in reports {
+        let detail = match &report.error {
+            Some(error) => error.clone(),
+            None => {
+                let bytes = report
+                    .outputs
+                    .iter()
+                    .map(|output| output.bytes)
+                    .sum::<usize>();
+                let output = match report.outputs.as_slice() {
+                    [output] => output.path.display().to_string(),
+                    outputs => format!("{} files", outputs.len()),
+                };
+                format!(
+                    "{output} ({bytes} bytes, {} ms)",
+                    report.duration.as_millis()
+                )
+            }
+        };
+        let status = if report.error.is_some() {
+            "FAILED"
+        } else {
+            "ok"
+        };
+        eprintln!("  {status:<6}  {:<width$}  {detail}", report.input);
     }
+}
+
+/// Final `--message-format json` line: one entry per processed input.
+fn print_summary(reports: &[FileReport]) {
+    let files = reports
+        .iter()
+        .map(|report| {
+            serde_json::json!({
+                "input": report.input,
+                "status": if report.error.is_some() { "failed" } else { "ok" },
+                "outputs": report.outputs,
+                "pages": report.pages,
+                "bytes": report.outputs.iter().map(|output| output.bytes).sum::<usize>(),
+                "duration_ms": report.duration.as_millis() as u64,
+                "warnings": report.count(Severity::Warning),
+                "errors": report.count(Severity::Error),
+                "error": report.error,
+            })
+        })
+        .collect::<Vec<_>>();
+    let summary = serde_json::json!({
+        "type": "summary",
+        "success": reports.iter().all(|report| report.error.is_none()),
+        "files": files,
+    });
+    println!("{summary}");
+}
 
+fn deny_warnings(options: &ProcessOptions<'_>, report: &FileReport) -> Result<(), String> {
+    let count = report.count(Severity::Warning);
+    if options.deny_warnings && count > 0 {
+        return Err(format!(
+            "{count} warning(s) treated as errors (--deny-warnings)"
+        ));
+    }
     Ok(())
 }
 
+/// `--date` accepts `YYYY-MM-DD` (midnight UTC) or seconds since the epoch.
+fn parse_date_flag(value: &str) -> Result<i64, String> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .or_else(|| DocumentDate::parse(value).map(DocumentDate::unix_timestamp))
+        .ok_or_else(|| {
+            format!("invalid --date value: {value} (expected YYYY-MM-DD or Unix seconds)")
+        })
+}
+
+fn is_stdout(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// `notes/intro.md` → `notes/intro-typst`
+fn typst_project_dir(input: &Path) -> PathBuf {
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    input.with_file_name(format!("{stem}-typst"))
+}
+
 fn resolve_force_toc(no_toc: bool, toc: bool) -> Option<bool> {
     if no_toc {
         Some(false)
@@ -366,171 +1137,274 @@ fn read_stdin() -> Result<String, String> {
     Ok(input)
 }
 
-fn install_fonts() -> Result<(), String> {
+fn install_fonts(args: FontsInstallArgs) -> Result<(), String> {
+    let FontsInstallArgs {
+        packs,
+        mirror,
+        from,
+    } = args;
     let font_dir = user_font_dir()?;
-    fs::create_dir_all(&font_dir).map_err(|e| format!("create fonts dir: {e}"))?;
-
-    let client = reqwest::blocking::Client::new();
-    let mut downloaded_any = false;
-
-    for (file_name, url) in FONT_DOWNLOADS {
-        let target = font_dir.join(file_name);
-        if target.is_file() {
-            println!("{file_name} already installed, skipping.");
-            continue;
-        }
-
-        download_font(&client, url, file_name, &target)?;
-        downloaded_any = true;
-    }
+    let packs = if packs.is_empty() {
+        vec![DEFAULT_PACK.to_string()]
+    } else {
+        packs
+    };
+    let packs = font_packs(&packs);
+    let source = match (
+        from,
+        mirror.or_else(|| std::env::var("MDXPORT_FONT_MIRROR").ok()),
+    ) {
+        (Some(path), _) => PackSource::local(path),
+        (None, Some(base)) => PackSource::Mirror(base),
+        (None, None) => PackSource::Upstream,
+    };
 
-    if downloaded_any {
-        println!("Fonts installed. CJK rendering ready.");
+    let mut installed_any = false;
+    fontpack::install(
+        &font_dir,
+        &packs,
+        &source,
+        |file, installed| match installed {
+            Installed::AlreadyPresent => println!("{} already installed, skipping.", file.name),
+            Installed::New => {
+                installed_any = true;
+                println!("Installed {} (checksum verified).", file.name);
+            }
+        },
+    )
+    .map_err(|e| format!("install fonts: {e}"))?;
+
+    let names = packs
+        .iter()
+        .map(|pack| pack.name)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if installed_any {
+        println!("Font packs installed: {names}.");
     } else {
-        println!("Fonts already installed.");
+        println!("Font packs already installed: {names}.");
     }
-
     Ok(())
 }
 
-fn list_fonts() -> Result<(), String> {
+fn remove_fonts(args: FontsRemoveArgs) -> Result<(), String> {
     let font_dir = user_font_dir()?;
-    let fonts = list_user_font_files(&font_dir).map_err(|e| format!("list fonts: {e}"))?;
-
-    if fonts.is_empty() {
-        println!("No fonts found in {}", font_dir.display());
-        return Ok(());
+    let removed = fontpack::remove(&font_dir, &font_packs(&args.packs))
+        .map_err(|e| format!("remove fonts: {e}"))?;
+    if removed.is_empty() {
+        println!("Nothing to remove.");
     }
-
-    for font in fonts {
-        println!("{}", font.display());
+    for name in removed {
+        println!("Removed {name}.");
     }
-
     Ok(())
 }
 
-fn download_font(
-    client: &reqwest::blocking::Client,
-    url: &str,
-    file_name: &str,
-    destination: &Path,
-) -> Result<(), String> {
-    let mut response = client
-        .get(url)
-        .send()
-        .map_err(|e| format!("download {file_name}: {e}"))?;
-    if !response.status().is_success() {
-        return Err(format!(
-            "download {file_name}: server returned {}",
-            response.status()
-        ));
+fn verify_fonts() -> Result<(), String> {
+    let font_dir = user_font_dir()?;
+    let files = fontpack::verify(&font_dir).map_err(|e| format!("verify fonts: {e}"))?;
+    if files.is_empty() {
+        println!("No font packs installed in {}.", font_dir.display());
+        return Ok(());
     }
 
-    let temp_path = destination.with_extension("part");
-    let mut output =
-        fs::File::create(&temp_path).map_err(|e| format!("create {file_name}: {e}"))?;
-
-    let total = response.content_length();
-    let mut downloaded = 0_u64;
-    let mut buf = [0_u8; 64 * 1024];
-
-    loop {
-        let count = response
-            .read(&mut buf)
-            .map_err(|e| format!("read {file_name}: {e}"))?;
-        if count == 0 {
-            break;
+    let mut broken = 0;
+    for (name, status) in &files {
+        match status {
+            FileStatus::Ok => println!("ok          {name}"),
+            FileStatus::Missing => {
+                broken += 1;
+                println!("missing     {name}");
+            }
+            FileStatus::Modified { expected, actual } => {
+                broken += 1;
+                println!("modified    {name} (expected {expected}, got {actual})");
+            }
+        }
+    }
+    for pack in FONT_PACKS {
+        if fontpack::is_installed(&font_dir, pack) {
+            println!("Pack {} installed: {}.", pack.name, pack.description);
         }
-
-        output
-            .write_all(&buf[..count])
-            .map_err(|e| format!("write {file_name}: {e}"))?;
-        downloaded += count as u64;
-        print_download_progress(file_name, downloaded, total);
     }
 
-    output
-        .flush()
-        .map_err(|e| format!("flush {file_name}: {e}"))?;
-
-    fs::rename(&temp_path, destination).map_err(|e| format!("finalize {file_name}: {e}"))?;
-    eprintln!();
-
-    Ok(())
+    match broken {
+        0 => Ok(()),
+        broken => Err(format!(
+            "{broken} font file(s) missing or modified; run mdxport fonts remove and fonts install for their packs"
+        )),
+    }
 }
 
-fn print_download_progress(file_name: &str, downloaded: u64, total: Option<u64>) {
-    match total {
-        Some(total) if total > 0 => {
-            let percent = (downloaded as f64 / total as f64) * 100.0;
-            eprint!("\rDownloading {file_name}: {percent:.1}% ({downloaded}/{total} bytes)");
-        }
-        _ => {
-            eprint!("\rDownloading {file_name}: {downloaded} bytes");
+/// Look up packs by name; clap has already checked that they exist.
+fn font_packs(names: &[String]) -> Vec<&'static FontPack> {
+    let mut packs = Vec::new();
+    for pack in names.iter().filter_map(|name| find_pack(name)) {
+        if !packs
+            .iter()
+            .any(|known: &&FontPack| known.name == pack.name)
+        {
+            packs.push(pack);
         }
     }
-    let _ = io::stderr().flush();
+    packs
 }
 
-fn maybe_warn_missing_cjk_fonts(markdown: &str, has_user_fonts: bool, warned: &mut bool) {
-    if !*warned && !has_user_fonts && contains_cjk_char(markdown) {
-        eprintln!("{CJK_FONT_WARNING}");
-        *warned = true;
+fn list_fonts(args: FontArgs) -> Result<(), String> {
+    // Like a conversion read from stdin, include ./fonts
+    let fonts = FontSet::new(&args.options().with_project_fonts(Path::new("")));
+
+    let mut families: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for index in 0..fonts.len() {
+        if let Some(info) = fonts.book().info(index) {
+            families
+                .entry(info.family.to_lowercase())
+                .or_default()
+                .push(index);
+        }
     }
-}
 
-fn contains_cjk_char(text: &str) -> bool {
-    text.chars().any(|ch| {
-        let code = ch as u32;
-        (0x4E00..=0x9FFF).contains(&code)
-            || (0x3040..=0x309F).contains(&code)
-            || (0x30A0..=0x30FF).contains(&code)
-            || (0xAC00..=0xD7AF).contains(&code)
-            || (0x3000..=0x303F).contains(&code)
-    })
+    for faces in families.values_mut() {
+        faces.sort_by_key(|&index| {
+            fonts.book().info(index).map(|info| {
+                let variant = info.variant;
+                (variant.weight, variant.style, variant.stretch)
+            })
+        });
+        let family = fonts.book().info(faces[0]).map(|info| info.family.as_str());
+        println!("{}", family.unwrap_or_default());
+        for &index in faces.iter() {
+            let Some(info) = fonts.book().info(index) else {
+                continue;
+            };
+            let source = fonts.path(index).map_or_else(
+                || "(bundled)".to_string(),
+                |path| path.display().to_string(),
+            );
+            println!("  {:<20} {source}", style_name(&info.variant));
+        }
+    }
+    println!("{} families, {} fonts", families.len(), fonts.len());
+
+    Ok(())
 }
 
-fn user_font_dir_has_font_files() -> bool {
-    let Ok(font_dir) = user_font_dir() else {
-        return false;
+/// "Bold Italic", "Light", "Regular", … for a font variant.
+fn style_name(variant: &FontVariant) -> String {
+    let weight = match variant.weight.to_number() {
+        100 => "Thin".to_string(),
+        200 => "Extralight".to_string(),
+        300 => "Light".to_string(),
+        400 => "Regular".to_string(),
+        500 => "Medium".to_string(),
+        600 => "Semibold".to_string(),
+        700 => "Bold".to_string(),
+        800 => "Extrabold".to_string(),
+        900 => "Black".to_string(),
+        other => format!("Weight {other}"),
+    };
+    let style = match variant.style {
+        FontStyle::Normal => None,
+        FontStyle::Italic => Some("Italic"),
+        FontStyle::Oblique => Some("Oblique"),
     };
-    list_user_font_files(&font_dir)
-        .map(|fonts| !fonts.is_empty())
-        .unwrap_or(false)
+    match style {
+        Some(style) if weight == "Regular" => style.to_string(),
+        Some(style) => format!("{weight} {style}"),
+        None => weight,
+    }
 }
 
-fn list_user_font_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
-    if !dir.exists() {
-        return Ok(Vec::new());
+fn check_fonts(args: FontsCheckArgs) -> Result<(), String> {
+    let FontsCheckArgs {
+        input,
+        style,
+        custom_template,
+        fonts,
+    } = args;
+    let markdown =
+        fs::read_to_string(&input).map_err(|e| format!("read {}: {e}", input.display()))?;
+    let source_name = input.display().to_string();
+    let parsed = split_frontmatter(&markdown).map_err(|e| {
+        eprint!("{}", Diagnostic::from(&e).render(&source_name, &markdown));
+        "frontmatter parse failed".to_string()
+    })?;
+
+    let custom_template = custom_template
+        .map(|path| CustomTemplate::load(&path).map_err(|e| format!("read template: {e}")))
+        .transpose()?;
+    let fallbacks = match &custom_template {
+        Some(template) => FontFallbacks::of_template(&template.source),
+        None => Style::try_from(style.as_str())
+            .map_err(|e| e.to_string())?
+            .font_fallbacks(),
+    };
+    let fonts = FontSet::shared(
+        &fonts
+            .options()
+            .with_project_fonts(input.parent().unwrap_or(Path::new("")))
+            .with_template_fonts(custom_template.as_ref().and_then(CustomTemplate::mount_dir)),
+    );
+
+    let text = document_text(&parsed.body, parsed.body_line_offset);
+    let diagnostics = check_coverage(&fonts, &fallbacks, &text);
+    for diagnostic in &diagnostics {
+        eprint!("{}", diagnostic.render(&source_name, &markdown));
     }
 
-    let mut files = Vec::new();
-    collect_user_font_files(dir, &mut files)?;
-    files.sort();
-    Ok(files)
+    let missing = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    match missing {
+        0 => {
+            println!("{source_name}: every character has a font");
+            Ok(())
+        }
+        1 => Err(format!("{source_name}: 1 character has no font")),
+        missing => Err(format!("{source_name}: {missing} characters have no font")),
+    }
 }
 
-fn collect_user_font_files(dir: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
-    let entries = fs::read_dir(dir)?;
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            collect_user_font_files(&path, out)?;
-            continue;
-        }
-        if is_supported_user_font_file(&path) {
-            out.push(path);
-        }
+/// Warn (once per run) when the document has CJK text that no available
+/// font covers, naming the font pack for its script.
+fn maybe_warn_missing_cjk_fonts(markdown: &str, fonts: &FontSet, warned: &AtomicBool) {
+    let Some(pack) = missing_cjk_pack(markdown, fonts) else {
+        return;
+    };
+    if warned.swap(true, Ordering::Relaxed) {
+        return;
     }
-    Ok(())
+    let Some(pack) = find_pack(pack) else {
+        return;
+    };
+    let alternative = if pack.name == "sc" {
+        " (or tc for Traditional Chinese)"
+    } else {
+        ""
+    };
+    eprintln!(
+        "Warning: CJK characters detected but no font covers them. Run mdxport fonts install {}{alternative} to download {} fonts (~{}MB).",
+        pack.name, pack.description, pack.size_mb
+    );
 }
 
-fn is_supported_user_font_file(path: &Path) -> bool {
-    path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| matches!(ext.to_ascii_lowercase().as_str(), "otf" | "ttf"))
-        .unwrap_or(false)
+/// The font pack for the first CJK script in `markdown` that `fonts` cannot
+/// render. The first character of each script stands in for the rest.
+fn missing_cjk_pack(markdown: &str, fonts: &FontSet) -> Option<&'static str> {
+    let scripts = [
+        // Kana before Han: Japanese text mixes both
+        (0x3040..=0x30FF, "jp"),
+        (0xAC00..=0xD7AF, "kr"),
+        (0x4E00..=0x9FFF, "sc"),
+    ];
+    scripts.into_iter().find_map(|(script, pack)| {
+        markdown
+            .chars()
+            .find(|&ch| script.contains(&(ch as u32)))
+            .filter(|&ch| fonts.resolve(&[], ch) == Resolution::Missing)
+            .map(|_| pack)
+    })
 }
 
 fn user_font_dir() -> Result<PathBuf, String> {
@@ -0,0 +1,304 @@
+//! Typst packages (`#import "@preview/codly:1.2.0"`) for custom templates.
+//!
+//! Packages are looked up as `{namespace}/{name}/{version}` under, in order:
+//! the directories in `MDXPORT_PACKAGE_PATH` (separated like `PATH`), Typst's
+//! local package directory (`~/.local/share/typst/packages` on Linux) and
+//! Typst's package cache (`~/.cache/typst/packages`). Missing `@preview`
+//! packages are downloaded into the cache unless offline, so packages
+//! fetched by mdxport and by the `typst` CLI are shared.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use typst::diag::{PackageError, PackageResult};
+use typst::syntax::package::PackageSpec;
+
+/// Finds package directories, downloading into the cache when allowed.
+/// Found packages are remembered, so each is only looked up once.
+pub struct PackageStore {
+    search: Vec<PathBuf>,
+    cache: Option<PathBuf>,
+    #[cfg_attr(not(feature = "cli"), allow(dead_code))]
+    offline: bool,
+    resolved: Mutex<HashMap<PackageSpec, PathBuf>>,
+}
+
+impl PackageStore {
+    /// `extra` directories followed by the standard ones; downloads are
+    /// skipped if `offline`.
+    pub fn new(extra: &[PathBuf], offline: bool) -> Self {
+        let mut search = extra.to_vec();
+        search.extend(
+            std::env::var_os("MDXPORT_PACKAGE_PATH")
+                .map(|paths| {
+                    std::env::split_paths(&paths)
+                        .filter(|path| !path.as_os_str().is_empty())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default(),
+        );
+        search.extend(data_dir().map(|dir| dir.join("typst").join("packages")));
+        let cache = cache_dir().map(|dir| dir.join("typst").join("packages"));
+        search.extend(cache.clone());
+        Self::with_paths(search, cache, offline)
+    }
+
+    fn with_paths(search: Vec<PathBuf>, cache: Option<PathBuf>, offline: bool) -> Self {
+        Self {
+            search,
+            cache,
+            offline,
+            resolved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The directory holding `spec`'s files.
+    pub fn resolve(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        if let Some(dir) = self
+            .resolved
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(spec)
+        {
+            return Ok(dir.clone());
+        }
+        // Not locked while downloading, so other packages resolve meanwhile.
+        // Failures are not remembered: the package may be installed or the
+        // network come back before the next compilation
+        let dir = self.find_or_download(spec)?;
+        Ok(self
+            .resolved
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(spec.clone())
+            .or_insert(dir)
+            .clone())
+    }
+
+    fn find_or_download(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        let subdir = Path::new(spec.namespace.as_str())
+            .join(spec.name.as_str())
+            .join(spec.version.to_string());
+        if let Some(dir) = self
+            .search
+            .iter()
+            .map(|root| root.join(&subdir))
+            .find(|dir| dir.is_dir())
+        {
+            return Ok(dir);
+        }
+
+        match &self.cache {
+            Some(cache) if spec.namespace == "preview" && !self.offline => {
+                download(spec, &cache.join(&subdir))
+            }
+            _ => Err(PackageError::NotFound(spec.clone())),
+        }
+    }
+}
+
+/// Upper bound for fetching one package, so a stalled registry cannot hang
+/// a build or watch mode.
+#[cfg(feature = "cli")]
+const DOWNLOAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Download a `@preview` package from the Typst package registry and unpack
+/// it into `dir`.
+#[cfg(feature = "cli")]
+fn download(spec: &PackageSpec, dir: &Path) -> PackageResult<PathBuf> {
+    let url = format!(
+        "https://packages.typst.org/preview/{}-{}.tar.gz",
+        spec.name, spec.version
+    );
+    let network = |e: reqwest::Error| PackageError::NetworkFailed(Some(e.to_string().into()));
+    let client = reqwest::blocking::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .map_err(network)?;
+    let response = client.get(&url).send().map_err(network)?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(PackageError::NotFound(spec.clone()));
+    }
+    if !response.status().is_success() {
+        return Err(PackageError::NetworkFailed(Some(
+            format!("{url}: server returned {}", response.status()).into(),
+        )));
+    }
+
+    // Unpack next to the destination and rename, so a concurrent run never
+    // sees a half-extracted package
+    let other = |e: std::io::Error| PackageError::Other(Some(e.to_string().into()));
+    let parent = dir
+        .parent()
+        .ok_or_else(|| other(std::io::Error::other("no parent")))?;
+    std::fs::create_dir_all(parent).map_err(other)?;
+    let temp = parent.join(format!(".{}-{}.part", spec.version, std::process::id()));
+    let _ = std::fs::remove_dir_all(&temp);
+    unpack_tar(flate2::read::GzDecoder::new(response), &temp).map_err(|e| {
+        let _ = std::fs::remove_dir_all(&temp);
+        PackageError::MalformedArchive(Some(e.to_string().into()))
+    })?;
+    if let Err(err) = std::fs::rename(&temp, dir) {
+        let _ = std::fs::remove_dir_all(&temp);
+        // Another run won the race
+        if !dir.is_dir() {
+            return Err(other(err));
+        }
+    }
+    Ok(dir.to_path_buf())
+}
+
+#[cfg(not(feature = "cli"))]
+fn download(spec: &PackageSpec, _dir: &Path) -> PackageResult<PathBuf> {
+    Err(PackageError::NotFound(spec.clone()))
+}
+
+/// Unpack the regular files and directories of a tar archive into `dir`,
+/// rejecting paths that would escape it.
+#[cfg(feature = "cli")]
+fn unpack_tar(archive: impl std::io::Read, dir: &Path) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+    use std::path::Component;
+
+    std::fs::create_dir_all(dir)?;
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("tar: unsafe path {}", path.display()),
+            ));
+        }
+        match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Directory => {
+                entry.unpack_in(dir)?;
+            }
+            // Links, devices, global pax headers: not part of packages
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn data_dir() -> Option<PathBuf> {
+    #[cfg(target_os = "macos")]
+    return home_dir().map(|home| home.join("Library/Application Support"));
+
+    #[cfg(target_os = "windows")]
+    return std::env::var_os("APPDATA").map(PathBuf::from);
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    return std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".local/share")));
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    #[cfg(target_os = "macos")]
+    return home_dir().map(|home| home.join("Library/Caches"));
+
+    #[cfg(target_os = "windows")]
+    return std::env::var_os("LOCALAPPDATA").map(PathBuf::from);
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    return std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".cache")));
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mdxport-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn resolves_packages_from_search_paths_in_order() {
+        let first = temp_dir("packages-first");
+        let second = temp_dir("packages-second");
+        std::fs::create_dir_all(second.join("local/demo/0.1.0")).unwrap();
+        let store = PackageStore::with_paths(vec![first.clone(), second.clone()], None, true);
+
+        let spec = PackageSpec::from_str("@local/demo:0.1.0").unwrap();
+        assert_eq!(
+            store.resolve(&spec).unwrap(),
+            second.join("local/demo/0.1.0")
+        );
+
+        let missing = PackageSpec::from_str("@preview/missing:1.0.0").unwrap();
+        assert!(matches!(
+            store.resolve(&missing),
+            Err(PackageError::NotFound(_))
+        ));
+        std::fs::remove_dir_all(&first).unwrap();
+        std::fs::remove_dir_all(&second).unwrap();
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn unpacks_tar_archives() {
+        let dir = temp_dir("package-tar");
+        let long_name = format!("src/{}.typ", "a-very-long-name".repeat(8));
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "src", std::io::empty())
+            .unwrap();
+        for (name, data) in [("typst.toml", "[package]"), (&long_name, "#let x = 1")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, name, data.as_bytes())
+                .unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+
+        unpack_tar(archive.as_slice(), &dir).unwrap();
+        assert_eq!(std::fs::read(dir.join("typst.toml")).unwrap(), b"[package]");
+        assert_eq!(std::fs::read(dir.join(&long_name)).unwrap(), b"#let x = 1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn rejects_tar_entries_escaping_the_package() {
+        let dir = temp_dir("package-tar-escape");
+        for name in ["../evil.typ", "/tmp/evil.typ", "src/../../evil.typ"] {
+            // `Builder` refuses such paths, so write the name field directly
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(4);
+            header.set_mode(0o644);
+            header.set_cksum();
+            let mut builder = tar::Builder::new(Vec::new());
+            builder.append(&header, b"evil".as_slice()).unwrap();
+            let archive = builder.into_inner().unwrap();
+
+            let err = unpack_tar(archive.as_slice(), &dir).unwrap_err();
+            assert!(err.to_string().contains("unsafe path"), "{name}: {err}");
+        }
+        assert!(!dir.parent().unwrap().join("evil.typ").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+//! `mdxport preview`: a localhost HTTP server showing the rendered pages as
+//! SVG, reloaded over Server-Sent Events whenever watch mode rebuilds.
+//!
+//! Everything is served from memory by a small `std::net` server: `/` is the
+//! viewer, `/page/N.svg` a page, `/state` the current state as JSON and
+//! `/events` the event stream, which sends that same JSON on every change.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::watch::{Publisher, WatchCommand, WatchError, watch_with};
+
+/// How often an idle event stream sends a comment, so closed connections are
+/// noticed and their threads exit.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+const VIEWER_HTML: &str = r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>mdxport preview</title>
+<style>
+  body { margin: 0; background: #525659; font-family: sans-serif; }
+  #pages { display: flex; flex-direction: column; align-items: center; gap: 16px; padding: 16px; }
+  #pages img { background: white; box-shadow: 0 2px 8px rgba(0, 0, 0, 0.4); max-width: 100%; }
+  #error { display: none; position: sticky; top: 0; background: #b00020; color: white;
+           padding: 8px 16px; white-space: pre-wrap; font-family: monospace; }
+</style>
+</head>
+<body>
+<div id="error"></div>
+<div id="pages"></div>
+<script>
+const pages = document.getElementById("pages");
+const error = document.getElementById("error");
+let first = true;
+new EventSource("/events").onmessage = (event) => {
+  const state = JSON.parse(event.data);
+  error.textContent = state.error || "";
+  error.style.display = state.error ? "block" : "none";
+  while (pages.children.length > state.pages.length) pages.lastChild.remove();
+  state.pages.forEach((version, index) => {
+    let img = pages.children[index];
+    if (!img) {
+      img = document.createElement("img");
+      img.alt = "page " + (index + 1);
+      pages.appendChild(img);
+    }
+    const src = "/page/" + (index + 1) + ".svg?v=" + version;
+    if (img.getAttribute("src") !== src) img.src = src;
+  });
+  if (!first && state.changed !== null) {
+    pages.children[state.changed]?.scrollIntoView({ behavior: "smooth", block: "start" });
+  }
+  first = false;
+};
+</script>
+</body>
+</html>
+"#;
+
+/// Serve `path` on `addr`, rebuilding it as SVG pages on every change.
+/// Runs until the watcher fails.
+pub fn preview(path: PathBuf, command: &WatchCommand, addr: &str) -> Result<(), WatchError> {
+    let server = PreviewServer::start(addr).map_err(WatchError::Io)?;
+    let addr = server.local_addr();
+    println!("preview at http://{addr}/");
+    watch_with(&[path], command, &Publisher::Preview(&server))
+}
+
+/// The preview HTTP server, accepting connections on a background thread.
+pub struct PreviewServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<PreviewState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct PreviewState {
+    /// Bumped on every publish; event streams wait for it to move.
+    revision: u64,
+    pages: Vec<Vec<u8>>,
+    /// Revision each page last changed in, used to bust the browser cache
+    /// for changed pages only.
+    page_revisions: Vec<u64>,
+    /// First page that differs from the previous build.
+    changed: Option<usize>,
+    error: Option<String>,
+}
+
+impl PreviewState {
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "revision": self.revision,
+            "pages": self.page_revisions,
+            "changed": self.changed,
+            "error": self.error,
+        })
+        .to_string()
+    }
+}
+
+impl PreviewServer {
+    /// Bind `addr` (port 0 picks a free port) and start serving.
+    pub fn start(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(PreviewState::default()),
+            changed: Condvar::new(),
+        });
+
+        let accept_shared = Arc::clone(&shared);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = Arc::clone(&accept_shared);
+                std::thread::spawn(move || {
+                    // A client hanging up mid-response is not our problem
+                    let _ = handle_connection(stream, &shared);
+                });
+            }
+        });
+
+        Ok(Self { addr, shared })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Show a new build. Clears any error shown by [`Self::publish_error`].
+    pub fn publish(&self, pages: Vec<Vec<u8>>) {
+        let mut state = self.shared.lock();
+        state.revision += 1;
+        let revision = state.revision;
+
+        let changed = (0..pages.len()).find(|&index| state.pages.get(index) != Some(&pages[index]));
+        state.page_revisions.truncate(pages.len());
+        for index in changed.into_iter().flat_map(|first| first..pages.len()) {
+            match state.page_revisions.get_mut(index) {
+                Some(page_revision) => *page_revision = revision,
+                None => state.page_revisions.push(revision),
+            }
+        }
+        state.changed = changed;
+        state.pages = pages;
+        state.error = None;
+        drop(state);
+        self.shared.changed.notify_all();
+    }
+
+    /// Show a failed build; the last good pages stay visible.
+    pub fn publish_error(&self, message: String) {
+        let mut state = self.shared.lock();
+        state.revision += 1;
+        state.changed = None;
+        state.error = Some(message);
+        drop(state);
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, PreviewState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn handle_connection(stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain the headers; nothing in them matters here
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    let path = target.split('?').next().unwrap_or_default();
+    let mut stream = stream;
+
+    if method != "GET" {
+        return respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"GET only",
+        );
+    }
+    match path {
+        "/" => respond(
+            &mut stream,
+            "200 OK",
+            "text/html; charset=utf-8",
+            VIEWER_HTML.as_bytes(),
+        ),
+        "/state" => {
+            let json = shared.lock().to_json();
+            respond(&mut stream, "200 OK", "application/json", json.as_bytes())
+        }
+        "/events" => stream_events(&mut stream, shared),
+        _ => {
+            let page = path
+                .strip_prefix("/page/")
+                .and_then(|rest| rest.strip_suffix(".svg"))
+                .and_then(|number| number.parse::<usize>().ok())
+                .and_then(|number| number.checked_sub(1))
+                .and_then(|index| shared.lock().pages.get(index).cloned());
+            match page {
+                Some(svg) => respond(&mut stream, "200 OK", "image/svg+xml", &svg),
+                None => respond(&mut stream, "404 Not Found", "text/plain", b"not found"),
+            }
+        }
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// Send the current state, then the state after every change, until the
+/// client goes away.
+fn stream_events(stream: &mut TcpStream, shared: &Shared) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n"
+    )?;
+
+    let mut state = shared.lock();
+    let mut sent = None;
+    loop {
+        if sent != Some(state.revision) {
+            sent = Some(state.revision);
+            let json = state.to_json();
+            drop(state);
+            write!(stream, "data: {json}\n\n")?;
+            stream.flush()?;
+            state = shared.lock();
+            continue;
+        }
+        let (next, timeout) = shared
+            .changed
+            .wait_timeout(state, KEEP_ALIVE)
+            .unwrap_or_else(|e| e.into_inner());
+        state = next;
+        if timeout.timed_out() {
+            drop(state);
+            stream.write_all(b": keep-alive\n\n")?;
+            stream.flush()?;
+            state = shared.lock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn get(addr: SocketAddr, path: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).expect("connect");
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("header end");
+        let head = String::from_utf8_lossy(&response[..split]).into_owned();
+        (head, response[split + 4..].to_vec())
+    }
+
+    fn next_event(reader: &mut BufReader<TcpStream>) -> serde_json::Value {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if let Some(data) = line.strip_prefix("data: ") {
+                return serde_json::from_str(data.trim()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn serves_viewer_and_pages() {
+        let server = PreviewServer::start("127.0.0.1:0").unwrap();
+        server.publish(vec![b"<svg>1</svg>".to_vec(), b"<svg>2</svg>".to_vec()]);
+        let addr = server.local_addr();
+
+        let (head, body) = get(addr, "/");
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(String::from_utf8_lossy(&body).contains("EventSource(\"/events\")"));
+
+        let (head, body) = get(addr, "/page/2.svg?v=1");
+        assert!(head.contains("Content-Type: image/svg+xml"));
+        assert_eq!(body, b"<svg>2</svg>");
+
+        let (head, _) = get(addr, "/page/3.svg");
+        assert!(head.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn events_report_first_changed_page() {
+        let server = PreviewServer::start("127.0.0.1:0").unwrap();
+        server.publish(vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        write!(stream, "GET /events HTTP/1.1\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(stream);
+        let initial = next_event(&mut reader);
+        assert_eq!(initial["pages"], serde_json::json!([1, 1, 1]));
+
+        server.publish(vec![b"a".to_vec(), b"B".to_vec(), b"c".to_vec()]);
+        let event = next_event(&mut reader);
+        assert_eq!(event["changed"], 1);
+        assert_eq!(event["pages"], serde_json::json!([1, 2, 2]));
+
+        server.publish_error("compile failed".to_string());
+        let event = next_event(&mut reader);
+        assert_eq!(event["error"], "compile failed");
+        assert_eq!(event["pages"], serde_json::json!([1, 2, 2]));
+    }
+}
@@ -3,11 +3,15 @@
 #let body-fonts = title-fonts
 #let code-fonts = ("IBM Plex Mono", "JetBrains Mono", "DejaVu Sans Mono", "Consolas")
 
+// Pass the frontmatter to `article` as `meta`
+#let mdxport-meta = true
+
 #let article(
   title: none,
   authors: (),
   lang: "en",
   toc: false,
+  meta: (:),
   body,
 ) = {
   set text(font: body-fonts, size: 11pt, lang: lang)
@@ -1,4 +1,9 @@
+use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use crate::convert::DocumentMetadata;
+use crate::frontmatter::DocumentDate;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Style {
@@ -34,6 +39,8 @@ impl TryFrom<&str> for Style {
 }
 
 impl Style {
+    const ALL: [Self; 2] = [Self::ModernTech, Self::ClassicEditorial];
+
     fn source(self) -> &'static str {
         match self {
             Self::ModernTech => include_str!("modern_tech.typ"),
@@ -42,6 +49,203 @@ impl Style {
     }
 }
 
+impl Style {
+    /// The font fallback lists of this style.
+    pub fn font_fallbacks(self) -> FontFallbacks {
+        FontFallbacks::of_template(self.source())
+    }
+}
+
+/// The font fallback lists a template declares as `#let title-fonts = (..)`,
+/// `#let body-fonts = ..` and `#let code-fonts = ..`, like the built-in
+/// styles. Lists a template does not declare this way are empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FontFallbacks {
+    pub title: Vec<String>,
+    pub body: Vec<String>,
+    pub code: Vec<String>,
+}
+
+impl FontFallbacks {
+    pub fn of_template(source: &str) -> Self {
+        let mut fallbacks = Self::default();
+        for line in source.lines() {
+            let Some((name, value)) = line
+                .trim()
+                .strip_prefix("#let ")
+                .and_then(|rest| rest.split_once('='))
+            else {
+                continue;
+            };
+            let Some(name) = name.trim().strip_suffix("-fonts") else {
+                continue;
+            };
+            let value = value.trim();
+            let families = match value.strip_suffix("-fonts") {
+                // `#let body-fonts = title-fonts`
+                Some(alias) => fallbacks.get(alias).cloned().unwrap_or_default(),
+                None => quoted_strings(value),
+            };
+            if let Some(list) = fallbacks.get_mut(name) {
+                *list = families;
+            }
+        }
+        fallbacks
+    }
+
+    fn get(&self, name: &str) -> Option<&Vec<String>> {
+        match name {
+            "title" => Some(&self.title),
+            "body" => Some(&self.body),
+            "code" => Some(&self.code),
+            _ => None,
+        }
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Vec<String>> {
+        match name {
+            "title" => Some(&mut self.title),
+            "body" => Some(&mut self.body),
+            "code" => Some(&mut self.code),
+            _ => None,
+        }
+    }
+}
+
+/// The string literals in a Typst expression such as `("A", "B")`.
+fn quoted_strings(value: &str) -> Vec<String> {
+    value
+        .split('"')
+        .skip(1)
+        .step_by(2)
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Virtual directory a multi-file template is mounted at.
+pub const TEMPLATE_MOUNT: &str = "/_template";
+
+/// Line by which a template asks for the frontmatter as a `meta:` argument
+/// to `article` (see [`DocumentMetadata::meta`]).
+pub const META_DECLARATION: &str = "#let mdxport-meta = true";
+
+/// Entry files looked for in a template directory, in order.
+pub const TEMPLATE_ENTRY_FILES: [&str; 2] = ["template.typ", "main.typ"];
+
+/// A custom template: Typst source defining `article`, either on its own or
+/// as the entry file of a directory that also holds the files it imports,
+/// images and fonts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomTemplate {
+    /// Source of the template file or of the directory's entry file.
+    pub source: String,
+    /// Template directory and its entry file name, for multi-file templates.
+    pub dir: Option<(PathBuf, String)>,
+}
+
+impl CustomTemplate {
+    /// Read a `.typ` file, or a directory with one of
+    /// [`TEMPLATE_ENTRY_FILES`].
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        if !path.is_dir() {
+            return Ok(Self::from(std::fs::read_to_string(path)?));
+        }
+        let Some(entry) = TEMPLATE_ENTRY_FILES
+            .iter()
+            .find(|name| path.join(name).is_file())
+        else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "{} has no {}",
+                    path.display(),
+                    TEMPLATE_ENTRY_FILES.join(" or ")
+                ),
+            ));
+        };
+        Ok(Self {
+            source: std::fs::read_to_string(path.join(entry))?,
+            dir: Some((path.to_path_buf(), (*entry).to_string())),
+        })
+    }
+
+    /// The directory to mount at [`TEMPLATE_MOUNT`], if any.
+    pub fn mount_dir(&self) -> Option<&Path> {
+        self.dir.as_ref().map(|(dir, _)| dir.as_path())
+    }
+
+    /// The files a document built with this template depends on.
+    pub fn files(&self) -> Vec<PathBuf> {
+        match self.mount_dir() {
+            Some(dir) => files_in(dir),
+            None => Vec::new(),
+        }
+    }
+
+    /// Typst placed before the document: the template itself, or an import
+    /// of the mounted entry file so its relative paths resolve inside the
+    /// template directory.
+    pub fn prelude(&self) -> Cow<'_, str> {
+        let Some((_, entry)) = &self.dir else {
+            return Cow::Borrowed(&self.source);
+        };
+        // `compose` only sees the prelude, so repeat the entry file's
+        // declaration; the import brings the same value into scope
+        let declaration = if accepts_meta(&self.source) {
+            format!("\n{META_DECLARATION}")
+        } else {
+            String::new()
+        };
+        Cow::Owned(format!(
+            "#import \"{TEMPLATE_MOUNT}/{}\": *{declaration}",
+            escape_string(entry)
+        ))
+    }
+}
+
+/// Every file below `dir`, recursively, sorted.
+pub(crate) fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+impl From<String> for CustomTemplate {
+    fn from(source: String) -> Self {
+        Self { source, dir: None }
+    }
+}
+
+impl From<&str> for CustomTemplate {
+    fn from(source: &str) -> Self {
+        Self::from(source.to_string())
+    }
+}
+
+/// Length in bytes of the built-in style `source` was composed from, or
+/// `None` if it uses a custom template.
+pub(crate) fn builtin_template_len(source: &str) -> Option<usize> {
+    Style::ALL
+        .iter()
+        .map(|style| style.source())
+        .find(|template| source.starts_with(template))
+        .map(str::len)
+}
+
 pub fn compose_document(
     style: Style,
     title: Option<&str>,
@@ -50,44 +254,37 @@ pub fn compose_document(
     toc: bool,
     body: &str,
 ) -> String {
-    let title_value = title.filter(|v| !v.trim().is_empty()).map_or_else(
-        || "none".to_string(),
-        |v| format!("\"{}\"", escape_string(v)),
-    );
-
-    let authors_value = if authors.is_empty() {
-        "()".to_string()
-    } else {
-        let formatted = authors
-            .iter()
-            .map(|author| format!("\"{}\"", escape_string(author)))
-            .collect::<Vec<_>>()
-            .join(", ");
-        // Typst requires trailing comma for single-element tuples: ("a",) not ("a")
-        if authors.len() == 1 {
-            format!("({formatted},)")
-        } else {
-            format!("({formatted})")
-        }
-    };
+    compose(
+        style.source(),
+        title,
+        authors,
+        lang,
+        toc,
+        body,
+        &DocumentMetadata::default(),
+    )
+}
 
-    let mut source = String::new();
-    source.push_str(style.source());
-    source.push_str("\n\n");
-    source.push_str(&format!(
-        "#article(title: {title_value}, authors: {authors_value}, lang: \"{}\", toc: {toc})[",
-        escape_string(lang),
-    ));
-    source.push('\n');
-    source.push_str(body);
-    source.push('\n');
-    source.push_str("]\n");
-    source
+/// Like [`compose_document`], additionally recording keywords, subject and
+/// date in the PDF metadata.
+pub fn compose_document_with_metadata(
+    style: Style,
+    title: Option<&str>,
+    authors: &[String],
+    lang: &str,
+    toc: bool,
+    body: &str,
+    metadata: &DocumentMetadata,
+) -> String {
+    compose(style.source(), title, authors, lang, toc, body, metadata)
 }
 
 /// Compose a Typst document using a custom template string.
 ///
 /// The template must define `#let article(title: none, authors: (), lang: "en", toc: false, body)`.
+/// If it contains the line [`META_DECLARATION`], `article` must also take
+/// `meta`, which receives the frontmatter as a dictionary (see
+/// [`DocumentMetadata::meta`]).
 pub fn compose_document_with_custom(
     template: &str,
     title: Option<&str>,
@@ -95,41 +292,208 @@ pub fn compose_document_with_custom(
     lang: &str,
     toc: bool,
     body: &str,
+) -> String {
+    compose(
+        template,
+        title,
+        authors,
+        lang,
+        toc,
+        body,
+        &DocumentMetadata::default(),
+    )
+}
+
+/// Like [`compose_document_with_custom`], additionally recording keywords,
+/// subject and date in the PDF metadata.
+pub fn compose_document_with_custom_metadata(
+    template: &str,
+    title: Option<&str>,
+    authors: &[String],
+    lang: &str,
+    toc: bool,
+    body: &str,
+    metadata: &DocumentMetadata,
+) -> String {
+    compose(template, title, authors, lang, toc, body, metadata)
+}
+
+/// Byte offset at which `body` was embedded in `source` by one of the
+/// `compose_document*` functions, for shifting a
+/// [`SourceMap`](crate::diagnostic::SourceMap).
+pub fn body_offset(source: &str, body: &str) -> Option<usize> {
+    let offset = source.len().checked_sub(body.len() + BODY_SUFFIX.len())?;
+    source
+        .get(offset..)?
+        .strip_prefix(body)
+        .filter(|rest| *rest == BODY_SUFFIX)
+        .map(|_| offset)
+}
+
+const BODY_SUFFIX: &str = "\n]\n";
+
+fn compose(
+    template: &str,
+    title: Option<&str>,
+    authors: &[String],
+    lang: &str,
+    toc: bool,
+    body: &str,
+    metadata: &DocumentMetadata,
 ) -> String {
     let title_value = title.filter(|v| !v.trim().is_empty()).map_or_else(
         || "none".to_string(),
         |v| format!("\"{}\"", escape_string(v)),
     );
+    let authors_value = string_array(authors);
 
-    let authors_value = if authors.is_empty() {
-        "()".to_string()
-    } else {
-        let formatted = authors
-            .iter()
-            .map(|author| format!("\"{}\"", escape_string(author)))
-            .collect::<Vec<_>>()
-            .join(", ");
-        if authors.len() == 1 {
-            format!("({formatted},)")
-        } else {
-            format!("({formatted})")
-        }
-    };
+    // `set document` fills the PDF info dictionary for any template, as
+    // long as the template itself does not override it
+    let mut document_args = vec![
+        format!("title: {title_value}"),
+        format!("author: {authors_value}"),
+        format!("keywords: {}", string_array(&metadata.keywords)),
+    ];
+    if let Some(subject) = metadata.subject.as_deref() {
+        document_args.push(format!("description: \"{}\"", escape_string(subject)));
+    }
+    if let Some(date) = metadata.date {
+        document_args.push(format!("date: {}", typst_date(date)));
+    }
 
     let mut source = String::new();
     source.push_str(template);
     source.push_str("\n\n");
+    source.push_str(&format!("#set document({})\n", document_args.join(", ")));
+    let meta = if accepts_meta(template) {
+        format!(", meta: {}", typst_dictionary(&metadata.meta))
+    } else {
+        String::new()
+    };
     source.push_str(&format!(
-        "#article(title: {title_value}, authors: {authors_value}, lang: \"{}\", toc: {toc})[",
+        "#article(title: {title_value}, authors: {authors_value}, lang: \"{}\", toc: {toc}{meta})[",
         escape_string(lang),
     ));
     source.push('\n');
     source.push_str(body);
-    source.push('\n');
-    source.push_str("]\n");
+    source.push_str(BODY_SUFFIX);
     source
 }
 
+/// Whether `template` declares [`META_DECLARATION`] on a line of its own,
+/// spelled with any spacing and optionally followed by `;` or a `//`
+/// comment. The declaration is explicit because `article`'s signature cannot
+/// be read reliably: it may take `..args` or be imported from another file.
+fn accepts_meta(template: &str) -> bool {
+    template.lines().any(|line| {
+        let Some((name, value)) = line
+            .trim()
+            .strip_prefix("#let")
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .and_then(|rest| rest.split_once('='))
+        else {
+            return false;
+        };
+        let value = value.split_once("//").map_or(value, |(value, _)| value);
+        name.trim() == "mdxport-meta" && value.trim().trim_end_matches(';').trim_end() == "true"
+    })
+}
+
+/// A YAML mapping as a Typst dictionary, e.g. `("version": 2, "tags": ("a",))`.
+/// Values keep their YAML types, except that strings reading as a
+/// [`DocumentDate`] become Typst dates at any depth.
+fn typst_dictionary(mapping: &serde_yaml::Mapping) -> String {
+    if mapping.is_empty() {
+        return "(:)".to_string();
+    }
+    let entries = mapping
+        .iter()
+        .map(|(key, value)| {
+            let key = match key {
+                serde_yaml::Value::String(key) => key.clone(),
+                other => typst_value(other).trim_matches('"').to_string(),
+            };
+            format!("{}: {}", typst_string(&key), typst_value(value))
+        })
+        .collect::<Vec<_>>();
+    format!("({})", entries.join(", "))
+}
+
+fn typst_date(date: DocumentDate) -> String {
+    format!(
+        "datetime(year: {}, month: {}, day: {})",
+        date.year, date.month, date.day
+    )
+}
+
+fn typst_value(value: &serde_yaml::Value) -> String {
+    use serde_yaml::Value;
+
+    match value {
+        Value::Null => "none".to_string(),
+        Value::Bool(value) => value.to_string(),
+        Value::Number(number) => match (number.as_i64(), number.as_f64()) {
+            (Some(integer), _) => integer.to_string(),
+            (None, Some(float)) if float.is_nan() => "float.nan".to_string(),
+            (None, Some(float)) if float.is_infinite() => {
+                format!("{}float.inf", if float < 0.0 { "-" } else { "" })
+            }
+            // Debug formatting keeps the `.0` that makes it a float in Typst
+            (None, Some(float)) => format!("{float:?}"),
+            (None, None) => number.to_string(),
+        },
+        Value::String(text) => {
+            DocumentDate::parse(text).map_or_else(|| typst_string(text), typst_date)
+        }
+        Value::Sequence(items) => match items.as_slice() {
+            [] => "()".to_string(),
+            [item] => format!("({},)", typst_value(item)),
+            items => format!(
+                "({})",
+                items.iter().map(typst_value).collect::<Vec<_>>().join(", ")
+            ),
+        },
+        Value::Mapping(mapping) => typst_dictionary(mapping),
+        Value::Tagged(tagged) => typst_value(&tagged.value),
+    }
+}
+
+/// A Typst string literal keeping `text` intact, line breaks included.
+fn typst_string(text: &str) -> String {
+    let mut literal = String::with_capacity(text.len() + 2);
+    literal.push('"');
+    for ch in text.chars() {
+        match ch {
+            '\\' => literal.push_str("\\\\"),
+            '"' => literal.push_str("\\\""),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            ch => literal.push(ch),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn string_array(values: &[String]) -> String {
+    if values.is_empty() {
+        return "()".to_string();
+    }
+
+    let formatted = values
+        .iter()
+        .map(|value| format!("\"{}\"", escape_string(value)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    // Typst requires trailing comma for single-element tuples: ("a",) not ("a")
+    if values.len() == 1 {
+        format!("({formatted},)")
+    } else {
+        format!("({formatted})")
+    }
+}
+
 fn escape_string(input: &str) -> String {
     input
         .replace('\\', "\\\\")
@@ -171,6 +535,23 @@ mod tests {
         assert!(src.contains("toc: true"));
     }
 
+    #[test]
+    fn font_fallbacks_follow_aliases() {
+        let fallbacks = Style::ModernTech.font_fallbacks();
+        assert_eq!(
+            fallbacks.title.first().map(String::as_str),
+            Some("IBM Plex Sans")
+        );
+        assert_eq!(fallbacks.body, fallbacks.title);
+        assert!(fallbacks.code.contains(&"DejaVu Sans Mono".to_string()));
+
+        let custom = FontFallbacks::of_template(
+            "#let code-fonts = \"Fira Code\"\n#let article(body) = body",
+        );
+        assert_eq!(custom.code, vec!["Fira Code".to_string()]);
+        assert!(custom.body.is_empty());
+    }
+
     #[test]
     fn compose_no_title() {
         let src = compose_document(Style::ModernTech, None, &[], "en", false, "body");
@@ -201,6 +582,43 @@ mod tests {
         assert!(src.contains("title: \"T\""));
     }
 
+    #[test]
+    fn compose_sets_document_metadata() {
+        let metadata = DocumentMetadata {
+            keywords: vec!["typst".into()],
+            subject: Some("Notes".into()),
+            date: Some(crate::frontmatter::DocumentDate {
+                year: 2024,
+                month: 5,
+                day: 1,
+            }),
+            ..DocumentMetadata::default()
+        };
+        let src = compose_document_with_metadata(
+            Style::ModernTech,
+            Some("T"),
+            &["A".into()],
+            "en",
+            false,
+            "body",
+            &metadata,
+        );
+        assert!(src.contains(
+            "#set document(title: \"T\", author: (\"A\",), keywords: (\"typst\",), description: \"Notes\", date: datetime(year: 2024, month: 5, day: 1))"
+        ));
+
+        let src = compose_document(Style::ModernTech, None, &[], "en", false, "body");
+        assert!(src.contains("#set document(title: none, author: (), keywords: ())"));
+    }
+
+    #[test]
+    fn body_offset_locates_body() {
+        let src = compose_document(Style::ModernTech, None, &[], "en", false, "the body\n");
+        let offset = body_offset(&src, "the body\n").unwrap();
+        assert!(src[offset..].starts_with("the body\n"));
+        assert_eq!(body_offset(&src, "other"), None);
+    }
+
     #[test]
     fn escape_quotes_in_title() {
         let src = compose_document(
@@ -226,4 +644,106 @@ mod tests {
         );
         assert!(Style::try_from("nonexistent").is_err());
     }
+
+    #[test]
+    fn frontmatter_becomes_a_meta_dictionary() {
+        let meta: serde_yaml::Mapping = serde_yaml::from_str(
+            "department: \"R&D\"\nversion: 2\nratio: 0.5\ndraft: false\ndate: 2024-05-01\n\
+             reviewed: 2024-05-01\ntags: [a]\nowner: {name: Ada, date: 2024-05-02}\nempty: {}\n\
+             nothing: ~\n",
+        )
+        .unwrap();
+        assert_eq!(
+            typst_dictionary(&meta),
+            "(\"department\": \"R&D\", \"version\": 2, \"ratio\": 0.5, \"draft\": false, \
+             \"date\": datetime(year: 2024, month: 5, day: 1), \
+             \"reviewed\": datetime(year: 2024, month: 5, day: 1), \"tags\": (\"a\",), \
+             \"owner\": (\"name\": \"Ada\", \"date\": datetime(year: 2024, month: 5, day: 2)), \
+             \"empty\": (:), \"nothing\": none)"
+        );
+        // Invalid dates stay strings
+        let invalid: serde_yaml::Mapping = serde_yaml::from_str("date: 2023-02-29").unwrap();
+        assert_eq!(typst_dictionary(&invalid), "(\"date\": \"2023-02-29\")");
+        let note: serde_yaml::Mapping = serde_yaml::from_str("note: \"line\\nbreak\"").unwrap();
+        assert_eq!(typst_dictionary(&note), "(\"note\": \"line\\nbreak\")");
+
+        let metadata = DocumentMetadata {
+            meta,
+            ..DocumentMetadata::default()
+        };
+        let src = compose_document_with_metadata(
+            Style::ModernTech,
+            None,
+            &[],
+            "en",
+            false,
+            "x",
+            &metadata,
+        );
+        assert!(src.contains("toc: false, meta: (\"department\""));
+    }
+
+    #[test]
+    fn meta_is_only_passed_to_templates_declaring_it() {
+        let without = "#let article(title: none, meta: (:), body) = body";
+        assert!(!accepts_meta(without));
+        assert!(
+            compose_document_with_custom(without, None, &[], "en", false, "x")
+                .contains("toc: false)[")
+        );
+
+        let with = "#let mdxport-meta = true\n#let article(..args, body) = body";
+        assert!(accepts_meta(with));
+        for spelling in [
+            "#let mdxport-meta=true",
+            "  #let  mdxport-meta =  true  ",
+            "#let mdxport-meta = true // pass the frontmatter",
+            "#let mdxport-meta = true;",
+        ] {
+            assert!(accepts_meta(spelling), "{spelling}");
+        }
+        for spelling in [
+            "#let mdxport-meta = false",
+            "#let mdxport-meta = true-ish",
+            "#letmdxport-meta = true",
+            "// #let mdxport-meta = true",
+        ] {
+            assert!(!accepts_meta(spelling), "{spelling}");
+        }
+        assert!(
+            compose_document_with_custom(with, None, &[], "en", false, "x")
+                .contains("toc: false, meta: (:))[")
+        );
+
+        // A directory's entry file may import `article`; the prelude repeats
+        // the entry file's declaration for `compose`
+        let mut template = CustomTemplate::from("#import \"lib.typ\": article");
+        template.dir = Some((PathBuf::from("acme"), "template.typ".into()));
+        assert!(!accepts_meta(&template.prelude()));
+        template.source = format!("#import \"lib.typ\": article\n{META_DECLARATION}");
+        assert_eq!(
+            template.prelude(),
+            format!("#import \"/_template/template.typ\": *\n{META_DECLARATION}")
+        );
+    }
+
+    #[test]
+    fn template_directories_are_imported_from_the_mount() {
+        let single = CustomTemplate::from("#let article(body) = body");
+        assert_eq!(single.prelude(), "#let article(body) = body");
+        assert_eq!(single.mount_dir(), None);
+
+        let dir = std::env::temp_dir().join(format!("mdxport-template-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.typ"), "#let article(body) = body").unwrap();
+        let template = CustomTemplate::load(&dir).unwrap();
+        assert!(
+            template
+                .prelude()
+                .starts_with("#import \"/_template/main.typ\": *")
+        );
+        assert_eq!(template.source, "#let article(body) = body");
+        assert_eq!(template.files(), vec![dir.join("main.typ")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
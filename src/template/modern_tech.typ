@@ -3,11 +3,15 @@
 #let body-fonts = title-fonts
 #let code-fonts = ("JetBrains Mono", "DejaVu Sans Mono", "SFMono-Regular", "Consolas", "Menlo")
 
+// Pass the frontmatter to `article` as `meta`
+#let mdxport-meta = true
+
 #let article(
   title: none,
   authors: (),
   lang: "en",
   toc: false,
+  meta: (:),
   body,
 ) = {
   set text(font: body-fonts, lang: lang)
@@ -1,23 +1,53 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 
 use crate::{
-    compile::compile_typst_to_pdf,
-    convert::{ConvertOptions, convert_markdown_to_typst},
+    compile::{
+        CompileError, CompileOptions, IncrementalCompiler, OutputFormat, PdfStandard,
+        document_ident, evict_memoized, page_output_paths,
+    },
+    convert::{AssetSource, ConvertOptions, convert_markdown_to_typst},
+    diagnostic::Diagnostic,
+    fonts::{FontOptions, FontSet},
     frontmatter::split_frontmatter,
-    template::{Style, compose_document},
+    preview::PreviewServer,
+    template::{
+        CustomTemplate, Style, body_offset, compose_document_with_custom_metadata,
+        compose_document_with_metadata,
+    },
 };
 
+/// Editors save in bursts (write, chmod, rename); rebuild once the burst
+/// has been quiet for this long.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
 pub struct WatchCommand {
     pub style: Style,
+    /// Custom Typst template file or directory, re-read on every rebuild and
+    /// watched itself.
+    pub custom_template: Option<PathBuf>,
     pub output: Option<PathBuf>,
     pub multiple_inputs: bool,
     pub title_override: Option<String>,
     pub author_override: Option<String>,
     pub lang_override: Option<String>,
     pub force_toc: Option<bool>,
+    pub base_dir: Option<PathBuf>,
+    pub offline: bool,
+    /// Fonts to use; each document also gets the `fonts/` directory next to it.
+    pub fonts: FontOptions,
+    pub format: OutputFormat,
+    pub ppi: f32,
+    pub pdf_standard: PdfStandard,
+    pub timestamp: Option<i64>,
+    /// Skip writing output when a rebuild reports warnings.
+    pub deny_warnings: bool,
+    /// Keep the output of a Markdown file deleted from a watched directory.
+    pub keep_outputs: bool,
     pub verbose: bool,
 }
 
@@ -40,7 +70,60 @@ impl std::fmt::Display for WatchError {
 
 impl std::error::Error for WatchError {}
 
+/// A watched Markdown file and what its last build read.
+struct WatchedDocument {
+    path: PathBuf,
+    output: PathBuf,
+    /// Found in a watched directory rather than named on the command line;
+    /// forgotten (and its output deleted) when the file is removed.
+    discovered: bool,
+    compiler: IncrementalCompiler,
+    /// Normalized paths of the Markdown file, template and local images.
+    dependencies: HashSet<PathBuf>,
+    /// Files written by the last successful build.
+    written: Vec<PathBuf>,
+}
+
+impl WatchedDocument {
+    fn new(path: PathBuf, output: PathBuf, discovered: bool) -> Self {
+        Self {
+            path,
+            output,
+            discovered,
+            compiler: IncrementalCompiler::new(),
+            dependencies: HashSet::new(),
+            written: Vec::new(),
+        }
+    }
+}
+
+/// Build every input, then rebuild a document whenever it, the template or
+/// one of its local images changes.
+///
+/// Directory inputs are watched recursively: every Markdown file in them is
+/// built on start, new ones are built when created, and outputs mirror the
+/// directory layout under `command.output`.
+///
+/// For files, the parent directories are watched rather than the files
+/// themselves, so atomic saves (write a temporary file, rename it over the
+/// original) are still seen after the original inode is gone.
 pub fn watch_inputs(paths: &[PathBuf], command: &WatchCommand) -> Result<(), WatchError> {
+    watch_with(paths, command, &Publisher::Files)
+}
+
+/// Where rebuilt pages go.
+pub(crate) enum Publisher<'a> {
+    /// Write them to each document's output path.
+    Files,
+    /// Hand them to `mdxport preview`, which serves them over HTTP.
+    Preview(&'a PreviewServer),
+}
+
+pub(crate) fn watch_with(
+    paths: &[PathBuf],
+    command: &WatchCommand,
+    publisher: &Publisher<'_>,
+) -> Result<(), WatchError> {
     let (tx, rx) = std::sync::mpsc::channel();
     let mut watcher = RecommendedWatcher::new(
         move |res| {
@@ -50,48 +133,221 @@ pub fn watch_inputs(paths: &[PathBuf], command: &WatchCommand) -> Result<(), Wat
     )
     .map_err(WatchError::Notify)?;
 
-    let mut tracked_files = HashMap::<PathBuf, PathBuf>::new();
+    let mut roots = Vec::new();
+    let mut documents = Vec::new();
     for path in paths {
-        let watch_path = canonicalize(path);
-        watcher
-            .watch(&watch_path, RecursiveMode::NonRecursive)
-            .map_err(WatchError::Notify)?;
-        tracked_files.insert(watch_path.clone(), path.clone());
-        println!("watching {}", watch_path.display());
+        if path.is_dir() {
+            let root = std::fs::canonicalize(path).map_err(WatchError::Io)?;
+            watcher
+                .watch(&root, RecursiveMode::Recursive)
+                .map_err(WatchError::Notify)?;
+            let files = markdown_files(&root);
+            println!(
+                "watching {} ({} Markdown files)",
+                root.display(),
+                files.len()
+            );
+            for file in files {
+                let output = mirrored_output_path(&root, &file, command);
+                documents.push(WatchedDocument::new(file, output, true));
+            }
+            roots.push(root);
+        } else {
+            let output = resolve_output_path(path, command);
+            documents.push(WatchedDocument::new(path.clone(), output, false));
+            println!("watching {}", normalize(path).display());
+        }
     }
+    let mut watched_dirs = HashSet::new();
+
+    for document in &mut documents {
+        rebuild(document, command, publisher);
+    }
+    evict_memoized();
+    watch_dependency_dirs(&mut watcher, &roots, &documents, &mut watched_dirs)?;
 
     loop {
-        let event = rx
-            .recv()
-            .map_err(|e| WatchError::Io(std::io::Error::other(e.to_string())))?;
-        match event {
-            Ok(Event {
-                kind: EventKind::Modify(_) | EventKind::Create(_),
-                paths,
-                ..
-            }) => {
-                for changed in paths {
-                    let canonical = canonicalize(&changed);
-                    let Some(source_path) = tracked_files.get(&canonical) else {
-                        continue;
-                    };
-
-                    if let Err(err) = rebuild_one(source_path, command) {
-                        eprintln!("[watch] failed: {err}");
-                    } else if command.verbose {
-                        println!("[watch] updated {}", source_path.display());
-                    }
+        let changed = next_changes(&rx)?;
+
+        documents.retain_mut(|document| {
+            if !document.discovered || document.path.exists() {
+                return true;
+            }
+            forget(document, command);
+            false
+        });
+
+        for document in &mut documents {
+            if !document.dependencies.is_disjoint(&changed) {
+                rebuild(document, command, publisher);
+            }
+        }
+
+        let mut created = Vec::new();
+        for path in &changed {
+            let Some(root) = roots.iter().find(|root| path.starts_with(root)) else {
+                continue;
+            };
+            for file in markdown_files(path) {
+                let known = documents
+                    .iter()
+                    .chain(&created)
+                    .any(|document: &WatchedDocument| document.path == file);
+                if !known {
+                    let output = mirrored_output_path(root, &file, command);
+                    created.push(WatchedDocument::new(file, output, true));
                 }
             }
+        }
+        for mut document in created {
+            rebuild(&mut document, command, publisher);
+            documents.push(document);
+        }
+        evict_memoized();
+
+        watch_dependency_dirs(&mut watcher, &roots, &documents, &mut watched_dirs)?;
+    }
+}
+
+/// Drop a document whose source was deleted, removing its output unless
+/// `keep_outputs` is set.
+fn forget(document: &WatchedDocument, command: &WatchCommand) {
+    if !command.keep_outputs {
+        for path in &document.written {
+            if let Err(err) = std::fs::remove_file(path)
+                && err.kind() != std::io::ErrorKind::NotFound
+            {
+                eprintln!("[watch] failed to remove {}: {err}", path.display());
+            }
+        }
+    }
+    if command.verbose {
+        println!("[watch] removed {}", document.path.display());
+    }
+}
+
+/// `path` itself if it is a Markdown file, or every Markdown file below it
+/// if it is a directory, skipping hidden entries. Sorted for a stable build
+/// order.
+fn markdown_files(path: &Path) -> Vec<PathBuf> {
+    let is_hidden = |path: &Path| {
+        path.file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+    };
+    if is_hidden(path) {
+        return Vec::new();
+    }
+    if path.is_file() {
+        let is_markdown = path
+            .extension()
+            .is_some_and(|ext| ext == "md" || ext == "markdown");
+        return if is_markdown {
+            vec![path.to_path_buf()]
+        } else {
+            Vec::new()
+        };
+    }
+
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return Vec::new();
+    };
+    let mut entries = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect::<Vec<_>>();
+    entries.sort();
+    entries
+        .iter()
+        .flat_map(|entry| markdown_files(entry))
+        .collect()
+}
+
+/// Block until something changes, then gather the rest of the burst.
+/// Returns the normalized paths touched.
+fn next_changes(rx: &Receiver<notify::Result<Event>>) -> Result<HashSet<PathBuf>, WatchError> {
+    let mut changed = HashSet::new();
+    let mut event = rx
+        .recv()
+        .map_err(|e| WatchError::Io(std::io::Error::other(e.to_string())))?;
+    loop {
+        match event {
+            Ok(Event { kind, paths, .. }) if !matches!(kind, EventKind::Access(_)) => {
+                changed.extend(paths.iter().map(|path| normalize(path)));
+            }
             Ok(_) => {}
             Err(err) => return Err(WatchError::Notify(err)),
         }
+        event = match rx.recv_timeout(DEBOUNCE) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => return Ok(changed),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(WatchError::Io(std::io::Error::other("watcher stopped")));
+            }
+        };
+    }
+}
+
+/// Start watching the directory of every dependency not yet covered, e.g.
+/// an image directory referenced for the first time.
+fn watch_dependency_dirs(
+    watcher: &mut RecommendedWatcher,
+    roots: &[PathBuf],
+    documents: &[WatchedDocument],
+    watched_dirs: &mut HashSet<PathBuf>,
+) -> Result<(), WatchError> {
+    let dirs = documents
+        .iter()
+        .flat_map(|document| &document.dependencies)
+        .filter_map(|path| path.parent())
+        .filter(|dir| dir.is_dir());
+    for dir in dirs {
+        if watched_dirs.contains(dir) || roots.iter().any(|root| dir.starts_with(root)) {
+            continue;
+        }
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(WatchError::Notify)?;
+        watched_dirs.insert(dir.to_path_buf());
+    }
+    Ok(())
+}
+
+fn rebuild(document: &mut WatchedDocument, command: &WatchCommand, publisher: &Publisher<'_>) {
+    let started = Instant::now();
+    // Keep watching the Markdown file and template even if the build fails
+    document.dependencies = [Some(&document.path), command.custom_template.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|path| normalize(path))
+        .collect();
+
+    match rebuild_one(document, command, publisher) {
+        Err(err) => {
+            eprintln!("[watch] failed: {}: {err}", document.path.display());
+            if let Publisher::Preview(server) = publisher {
+                server.publish_error(format!("{}: {err}", document.path.display()));
+            }
+        }
+        Ok(()) if command.verbose => println!(
+            "[watch] updated {} in {} ms",
+            document.path.display(),
+            started.elapsed().as_millis()
+        ),
+        Ok(()) => {}
     }
 }
 
-fn rebuild_one(path: &Path, command: &WatchCommand) -> Result<(), String> {
+fn rebuild_one(
+    document: &mut WatchedDocument,
+    command: &WatchCommand,
+    publisher: &Publisher<'_>,
+) -> Result<(), String> {
+    let path = document.path.as_path();
     let source = std::fs::read_to_string(path).map_err(|e| format!("{e}"))?;
-    let parsed = split_frontmatter(&source).map_err(|e| format!("frontmatter: {e}"))?;
+    let source_name = path.display().to_string();
+    let parsed = split_frontmatter(&source).map_err(|e| {
+        eprint!("{}", Diagnostic::from(&e).render(&source_name, &source));
+        "frontmatter parse failed".to_string()
+    })?;
     let converted = convert_markdown_to_typst(
         &parsed.body,
         &parsed.frontmatter,
@@ -100,37 +356,167 @@ fn rebuild_one(path: &Path, command: &WatchCommand) -> Result<(), String> {
             author_override: command.author_override.clone(),
             lang_override: command.lang_override.clone(),
             force_toc: command.force_toc,
+            base_dir: command
+                .base_dir
+                .clone()
+                .or_else(|| path.parent().map(Path::to_path_buf)),
+            line_offset: parsed.body_line_offset,
         },
     )
     .map_err(|e| format!("{e}"))?;
-    let typst = compose_document(
-        command.style,
-        converted.title.as_deref(),
-        &converted.authors,
-        &converted.lang,
-        converted.toc,
-        &converted.body,
+    document.dependencies.extend(
+        converted
+            .assets
+            .iter()
+            .filter_map(|asset| match &asset.source {
+                AssetSource::Local(path) => Some(normalize(path)),
+                AssetSource::Remote(_) => None,
+            }),
+    );
+    for warning in &parsed.warnings {
+        eprint!("{}", warning.render(&source_name, &source));
+    }
+    for warning in &converted.warnings {
+        eprint!(
+            "{}",
+            Diagnostic::from(warning).render(&source_name, &source)
+        );
+    }
+
+    let template = command
+        .custom_template
+        .as_deref()
+        .map(|template_path| {
+            CustomTemplate::load(template_path)
+                .map_err(|e| format!("read template {}: {e}", template_path.display()))
+        })
+        .transpose()?;
+    let template_dir = template.as_ref().and_then(CustomTemplate::mount_dir);
+    document.dependencies.extend(
+        template
+            .iter()
+            .flat_map(CustomTemplate::files)
+            .map(|path| normalize(&path)),
     );
-    let output = resolve_output_path(path, command.output.as_deref(), command.multiple_inputs);
-    if let Some(parent) = output.parent()
+
+    let typst = match &template {
+        Some(template) => compose_document_with_custom_metadata(
+            &template.prelude(),
+            converted.title.as_deref(),
+            &converted.authors,
+            &converted.lang,
+            converted.toc,
+            &converted.body,
+            &converted.metadata,
+        ),
+        None => compose_document_with_metadata(
+            command.style,
+            converted.title.as_deref(),
+            &converted.authors,
+            &converted.lang,
+            converted.toc,
+            &converted.body,
+            &converted.metadata,
+        ),
+    };
+    let compile_options = CompileOptions {
+        assets: converted.assets,
+        offline: command.offline,
+        format: command.format,
+        ppi: Some(command.ppi),
+        ident: Some(document_ident(path)),
+        pdf_standard: command.pdf_standard,
+        timestamp: command.timestamp,
+        source_map: Some(
+            converted
+                .source_map
+                .shifted(body_offset(&typst, &converted.body).unwrap_or_default()),
+        ),
+        source_path: Some(path.to_path_buf()),
+        fonts: Some(FontSet::shared(
+            &command
+                .fonts
+                .clone()
+                .with_project_fonts(path.parent().unwrap_or(Path::new("")))
+                .with_template_fonts(template_dir),
+        )),
+        template_dir: template_dir.map(Path::to_path_buf),
+        ..CompileOptions::default()
+    };
+    let compiled = document
+        .compiler
+        .compile(&typst, &compile_options)
+        .map_err(|e| match e {
+            CompileError::Typst(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    eprint!("{}", diagnostic.render(&source_name, &source));
+                }
+                format!("compile failed with {} error(s)", diagnostics.len())
+            }
+            e => format!("{e}"),
+        })?;
+    for warning in &compiled.warnings {
+        eprint!("{}", warning.render(&source_name, &source));
+    }
+    let warnings = parsed.warnings.len() + converted.warnings.len() + compiled.warnings.len();
+    if command.deny_warnings && warnings > 0 {
+        return Err(format!(
+            "{warnings} warning(s) treated as errors (--deny-warnings)"
+        ));
+    }
+
+    if let Publisher::Preview(server) = publisher {
+        server.publish(compiled.output);
+        return Ok(());
+    }
+
+    if let Some(parent) = document.output.parent()
         && !parent.as_os_str().is_empty()
     {
         std::fs::create_dir_all(parent).map_err(|e| format!("{e}"))?;
     }
-    compile_typst_to_pdf(&typst, &output).map_err(|e| format!("{e}"))?;
+    document.written.clear();
+    for (page_path, bytes) in page_output_paths(&document.output, compiled.output.len())
+        .into_iter()
+        .zip(&compiled.output)
+    {
+        std::fs::write(&page_path, bytes)
+            .map_err(|e| format!("write {}: {e}", page_path.display()))?;
+        document.written.push(page_path);
+    }
     Ok(())
 }
 
-fn resolve_output_path(input: &Path, output: Option<&Path>, multiple_inputs: bool) -> PathBuf {
-    match output {
-        Some(path) if multiple_inputs => path
+/// `docs/guide/intro.md` under root `docs` → `<output>/guide/intro.pdf`, or
+/// next to the source without `-o`.
+fn mirrored_output_path(root: &Path, input: &Path, command: &WatchCommand) -> PathBuf {
+    let extension = command.format.extension();
+    match (command.output.as_deref(), input.strip_prefix(root)) {
+        (Some(dir), Ok(relative)) => dir.join(relative).with_extension(extension),
+        _ => input.with_extension(extension),
+    }
+}
+
+fn resolve_output_path(input: &Path, command: &WatchCommand) -> PathBuf {
+    let extension = command.format.extension();
+    match command.output.as_deref() {
+        Some(path) if command.multiple_inputs => path
             .join(input.file_name().unwrap_or_default())
-            .with_extension("pdf"),
+            .with_extension(extension),
         Some(path) => path.to_path_buf(),
-        None => input.with_extension("pdf"),
+        None => input.with_extension(extension),
     }
 }
 
-fn canonicalize(path: &Path) -> PathBuf {
-    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+/// Absolute path with the parent directory canonicalized. Unlike
+/// `fs::canonicalize` this works for files that are momentarily missing,
+/// as during an atomic save.
+fn normalize(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) => std::fs::canonicalize(parent)
+            .unwrap_or_else(|_| parent.to_path_buf())
+            .join(name),
+        _ => absolute,
+    }
 }
@@ -1,32 +1,123 @@
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use mdxport::compile::compile_typst_to_pdf;
-use mdxport::convert::{ConvertOptions, convert_markdown_to_typst};
+use mdxport::compile::{
+    CompileError, CompileOptions, IncrementalCompiler, OutputFormat, compile_typst_to_files,
+    compile_typst_to_format, compile_typst_to_pdf_with_options, export_typst_project,
+    page_output_paths,
+};
+use mdxport::convert::{
+    Asset, AssetSource, ConvertOptions, ConvertedDocument, convert_markdown_to_typst,
+};
+use mdxport::diagnostic::{Diagnostic, Severity, SourcePosition, Stage};
 use mdxport::frontmatter::split_frontmatter;
-use mdxport::template::{Style, compose_document, compose_document_with_custom};
+use mdxport::template::{
+    Style, body_offset, compose_document, compose_document_with_custom,
+    compose_document_with_custom_metadata, compose_document_with_metadata,
+};
 
-/// Helper: full pipeline from markdown string to PDF bytes
-fn md_to_pdf(markdown: &str, style: Style) -> Vec<u8> {
+/// What an end-to-end test varies in the pipeline; images resolve against
+/// `tests/fixtures`.
+struct Pipeline<'a> {
+    style: Style,
+    /// Custom template source, used instead of `style`.
+    template: Option<&'a str>,
+    convert: ConvertOptions,
+    /// Assets and the source map are filled in from the conversion.
+    compile: CompileOptions,
+}
+
+impl Default for Pipeline<'_> {
+    fn default() -> Self {
+        Self {
+            style: Style::ModernTech,
+            template: None,
+            convert: ConvertOptions {
+                base_dir: Some(fixtures_dir()),
+                ..ConvertOptions::default()
+            },
+            compile: CompileOptions::default(),
+        }
+    }
+}
+
+impl From<Style> for Pipeline<'_> {
+    fn from(style: Style) -> Self {
+        Self {
+            style,
+            ..Self::default()
+        }
+    }
+}
+
+/// A document taken through the pipeline up to its Typst source.
+struct Prepared {
+    converted: ConvertedDocument,
+    source: String,
+    options: CompileOptions,
+}
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+/// Helper: markdown string to Typst source and compile options, as the CLI
+/// builds them
+fn md_to_typst<'a>(markdown: &str, pipeline: impl Into<Pipeline<'a>>) -> Prepared {
+    let pipeline = pipeline.into();
     let parsed = split_frontmatter(markdown).expect("frontmatter parse");
     let converted = convert_markdown_to_typst(
         &parsed.body,
         &parsed.frontmatter,
-        &ConvertOptions::default(),
+        &ConvertOptions {
+            line_offset: parsed.body_line_offset,
+            ..pipeline.convert
+        },
     )
     .expect("convert");
-    let source = compose_document(
-        style,
-        converted.title.as_deref(),
-        &converted.authors,
-        &converted.lang,
-        converted.toc,
-        &converted.body,
-    );
-    let tmp = Path::new("/tmp").join(format!("mdxport_test_{}.pdf", std::process::id()));
-    let bytes = compile_typst_to_pdf(&source, &tmp).expect("compile");
-    let _ = fs::remove_file(&tmp);
-    bytes
+    let source = match pipeline.template {
+        Some(template) => compose_document_with_custom_metadata(
+            template,
+            converted.title.as_deref(),
+            &converted.authors,
+            &converted.lang,
+            converted.toc,
+            &converted.body,
+            &converted.metadata,
+        ),
+        None => compose_document_with_metadata(
+            pipeline.style,
+            converted.title.as_deref(),
+            &converted.authors,
+            &converted.lang,
+            converted.toc,
+            &converted.body,
+            &converted.metadata,
+        ),
+    };
+    let options = CompileOptions {
+        assets: converted.assets.clone(),
+        source_map: Some(
+            converted
+                .source_map
+                .clone()
+                .shifted(body_offset(&source, &converted.body).expect("body offset")),
+        ),
+        ..pipeline.compile
+    };
+    Prepared {
+        converted,
+        source,
+        options,
+    }
+}
+
+/// Helper: full pipeline from markdown string to PDF bytes
+fn md_to_pdf<'a>(markdown: &str, pipeline: impl Into<Pipeline<'a>>) -> Vec<u8> {
+    let prepared = md_to_typst(markdown, pipeline);
+    mdxport::compile_typst_to_bytes(&prepared.source, &prepared.options)
+        .expect("compile")
+        .output
 }
 
 #[test]
@@ -48,6 +139,7 @@ fn parse_frontmatter_and_convert_core_syntax() {
             author_override: None,
             lang_override: None,
             force_toc: None,
+            ..ConvertOptions::default()
         },
     )
     .expect("convert");
@@ -66,27 +158,17 @@ fn parse_frontmatter_and_convert_core_syntax() {
 #[test]
 fn compose_document_includes_template_and_content() {
     let fixture = include_str!("fixtures/nested_tables.md");
-    let parsed = split_frontmatter(fixture).expect("frontmatter parse");
-
-    let converted = convert_markdown_to_typst(
-        &parsed.body,
-        &parsed.frontmatter,
-        &ConvertOptions {
-            title_override: Some("Fixture Title".into()),
-            author_override: None,
-            lang_override: Some("en".into()),
-            force_toc: Some(false),
+    let Prepared { source, .. } = md_to_typst(
+        fixture,
+        Pipeline {
+            convert: ConvertOptions {
+                title_override: Some("Fixture Title".into()),
+                lang_override: Some("en".into()),
+                force_toc: Some(false),
+                ..ConvertOptions::default()
+            },
+            ..Pipeline::default()
         },
-    )
-    .expect("convert");
-
-    let source = compose_document(
-        Style::ModernTech,
-        converted.title.as_deref(),
-        &converted.authors,
-        &converted.lang,
-        converted.toc,
-        &converted.body,
     );
 
     assert!(source.contains("#let article("));
@@ -96,33 +178,18 @@ fn compose_document_includes_template_and_content() {
 #[test]
 fn compile_pipeline_smoke_if_possible() {
     let fixture = include_str!("fixtures/simple.md");
-    let parsed = split_frontmatter(fixture).expect("frontmatter parse");
-
-    let converted = convert_markdown_to_typst(
-        &parsed.body,
-        &parsed.frontmatter,
-        &ConvertOptions {
-            title_override: None,
-            author_override: None,
-            lang_override: Some("en".into()),
-            force_toc: None,
+    let bytes = md_to_pdf(
+        fixture,
+        Pipeline {
+            style: Style::ClassicEditorial,
+            convert: ConvertOptions {
+                lang_override: Some("en".into()),
+                ..ConvertOptions::default()
+            },
+            ..Pipeline::default()
         },
-    )
-    .expect("convert");
-
-    let source = compose_document(
-        Style::ClassicEditorial,
-        converted.title.as_deref(),
-        &converted.authors,
-        &converted.lang,
-        converted.toc,
-        &converted.body,
     );
-
-    let tmp_path = Path::new("/tmp").join("mdxport_smoke_test.pdf");
-    let bytes = compile_typst_to_pdf(&source, &tmp_path).expect("compile should succeed");
     assert!(!bytes.is_empty());
-    let _ = fs::remove_file(&tmp_path);
 }
 
 // ── E2E tests ──────────────────────────────────────────────
@@ -196,14 +263,6 @@ $$
 #[test]
 fn e2e_custom_template() {
     let md = "# Custom\n\nHello from custom template.";
-    let parsed = split_frontmatter(md).unwrap();
-    let converted = convert_markdown_to_typst(
-        &parsed.body,
-        &parsed.frontmatter,
-        &ConvertOptions::default(),
-    )
-    .unwrap();
-
     // Minimal valid template
     let tmpl = r#"
 #let article(title: none, authors: (), lang: "en", toc: false, body) = {
@@ -213,19 +272,301 @@ fn e2e_custom_template() {
   body
 }
 "#;
-    let source = compose_document_with_custom(
-        tmpl,
-        converted.title.as_deref(),
-        &converted.authors,
-        &converted.lang,
-        converted.toc,
-        &converted.body,
+    let bytes = md_to_pdf(
+        md,
+        Pipeline {
+            template: Some(tmpl),
+            ..Pipeline::default()
+        },
     );
-    let tmp = Path::new("/tmp").join("mdxport_custom_tmpl.pdf");
-    let bytes = compile_typst_to_pdf(&source, &tmp).expect("custom template compile");
     assert!(bytes.len() > 500);
     assert_eq!(&bytes[..5], b"%PDF-");
-    let _ = fs::remove_file(&tmp);
+}
+
+#[test]
+fn e2e_custom_template_imports_local_package() {
+    let packages = std::env::temp_dir().join(format!("mdxport_packages_{}", std::process::id()));
+    let package = packages.join("local/boxed/0.1.0");
+    fs::create_dir_all(package.join("src")).unwrap();
+    fs::write(
+        package.join("typst.toml"),
+        "[package]\nname = \"boxed\"\nversion = \"0.1.0\"\nentrypoint = \"src/lib.typ\"\n",
+    )
+    .unwrap();
+    fs::write(
+        package.join("src/lib.typ"),
+        "#import \"util.typ\": pad\n#let boxed(body) = block(stroke: 1pt, inset: pad, body)\n",
+    )
+    .unwrap();
+    fs::write(package.join("src/util.typ"), "#let pad = 6pt\n").unwrap();
+
+    let tmpl = r#"
+#import "@local/boxed:0.1.0": boxed
+#let article(title: none, authors: (), lang: "en", toc: false, body) = boxed(body)
+"#;
+    let source = compose_document_with_custom(tmpl, None, &[], "en", false, "Hello");
+    let tmp = packages.join("out.pdf");
+    let options = CompileOptions {
+        offline: true,
+        package_paths: vec![packages.clone()],
+        ..CompileOptions::default()
+    };
+    let bytes = compile_typst_to_pdf_with_options(&source, &options, &tmp)
+        .expect("package import compile")
+        .output;
+    assert_eq!(&bytes[..5], b"%PDF-");
+
+    // Offline, missing packages are an error rather than a download
+    let missing = source.replace("@local/boxed:0.1.0", "@preview/mdxport-missing:0.1.0");
+    let Err(CompileError::Typst(diagnostics)) =
+        compile_typst_to_pdf_with_options(&missing, &options, &tmp)
+    else {
+        panic!("missing package must fail");
+    };
+    assert!(
+        diagnostics
+            .iter()
+            .any(|d| d.message.contains("mdxport-missing")),
+        "{diagnostics:?}"
+    );
+    let _ = fs::remove_dir_all(&packages);
+}
+
+#[test]
+fn e2e_template_directory_resolves_its_own_files() {
+    let root = std::env::temp_dir().join(format!("mdxport_template_dir_{}", std::process::id()));
+    let theme = root.join("acme");
+    fs::create_dir_all(theme.join("parts")).unwrap();
+    fs::write(
+        theme.join("template.typ"),
+        r#"#import "parts/colors.typ": brand
+#let article(title: none, authors: (), lang: "en", toc: false, body) = {
+  set text(fill: brand)
+  image("logo.svg", width: 2cm)
+  body
+}
+"#,
+    )
+    .unwrap();
+    fs::write(
+        theme.join("parts/colors.typ"),
+        "#let brand = rgb(\"#c0392b\")\n",
+    )
+    .unwrap();
+    fs::write(
+        theme.join("logo.svg"),
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="10" height="10"/></svg>"#,
+    )
+    .unwrap();
+
+    let template = mdxport::CustomTemplate::load(&theme).expect("load template directory");
+    assert_eq!(template.mount_dir(), Some(theme.as_path()));
+    let pdf = mdxport::markdown_to_pdf(
+        "# Branded\n\nHello.\n",
+        &mdxport::Options {
+            custom_template: Some(template.clone()),
+            ..mdxport::Options::default()
+        },
+    )
+    .expect("template directory compile");
+    assert_eq!(&pdf[..5], b"%PDF-");
+
+    // The exported project carries the template along
+    let source = compose_document_with_custom(&template.prelude(), None, &[], "en", false, "Hi");
+    let options = CompileOptions {
+        template_dir: Some(theme.clone()),
+        ..CompileOptions::default()
+    };
+    let out = root.join("project");
+    let written = export_typst_project(&source, &options, &out).expect("export");
+    assert!(written.contains(&out.join("_template/parts/colors.typ")));
+    assert!(out.join("_template/logo.svg").is_file());
+
+    // Directories without an entry file are rejected
+    assert!(mdxport::CustomTemplate::load(&theme.join("parts")).is_err());
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn e2e_embedded_images_all_formats() {
+    let md = "# Images\n\n![png](images/checker.png)\n\n![jpeg](images/checker.jpg)\n\n![gif](images/checker.gif)\n\n![svg](images/checker.svg)\n\n![missing](images/missing.png)\n";
+    let Prepared { converted, .. } = md_to_typst(md, Pipeline::default());
+    assert_eq!(converted.assets.len(), 4);
+    assert_eq!(converted.warnings.len(), 1);
+    assert_eq!(converted.warnings[0].line, 11);
+
+    let bytes = md_to_pdf(md, Pipeline::default());
+    assert_eq!(&bytes[..5], b"%PDF-");
+    assert!(bytes.windows(b"/Image".len()).any(|w| w == b"/Image"));
+}
+
+#[test]
+fn e2e_figures_and_references() {
+    let md = include_str!("fixtures/figures.md");
+    let Prepared { converted, .. } = md_to_typst(md, Pipeline::default());
+    assert!(converted.body.contains("<fig:arch>"));
+    assert!(converted.body.contains("<tbl:results>"));
+    assert!(converted.body.contains("<lst:main>"));
+
+    let bytes = md_to_pdf(md, Pipeline::default());
+    assert_eq!(&bytes[..5], b"%PDF-");
+}
+
+#[test]
+fn e2e_offline_remote_image_uses_placeholder() {
+    let md = "# Remote\n\n![badge](https://example.invalid/badge.png)\n";
+    let cache_dir = std::env::temp_dir().join(format!("mdxport_cache_{}", std::process::id()));
+    let pdf = mdxport::markdown_to_pdf(
+        md,
+        &mdxport::Options {
+            offline: true,
+            ..mdxport::Options::default()
+        },
+    );
+    // Offline mode must neither touch the network nor fail on a cache miss
+    assert!(pdf.is_ok(), "{:?}", pdf.err());
+
+    let bytes = md_to_pdf(
+        md,
+        Pipeline {
+            compile: CompileOptions {
+                offline: true,
+                cache_dir: Some(cache_dir.clone()),
+                ..CompileOptions::default()
+            },
+            ..Pipeline::default()
+        },
+    );
+    assert_eq!(&bytes[..5], b"%PDF-");
+    let _ = fs::remove_dir_all(&cache_dir);
+}
+
+#[test]
+fn e2e_export_typst_project() {
+    let md =
+        "# Export\n\n![png](images/checker.png)\n\n![badge](https://example.invalid/badge.png)\n";
+    let dir = std::env::temp_dir().join(format!("mdxport_export_{}", std::process::id()));
+    let cache_dir = dir.with_extension("cache");
+    let Prepared {
+        converted,
+        source,
+        options,
+    } = md_to_typst(
+        md,
+        Pipeline {
+            compile: CompileOptions {
+                offline: true,
+                cache_dir: Some(cache_dir.clone()),
+                ..CompileOptions::default()
+            },
+            ..Pipeline::default()
+        },
+    );
+    let written = export_typst_project(&source, &options, &dir).expect("export");
+    assert_eq!(written.len(), 3);
+    assert_eq!(fs::read_to_string(dir.join("main.typ")).unwrap(), source);
+    assert_eq!(
+        fs::read(dir.join("images/checker.png")).unwrap(),
+        fs::read(fixtures_dir().join("images/checker.png")).unwrap()
+    );
+
+    // Every asset must sit where main.typ expects it, relative to the project root
+    let exported = converted
+        .assets
+        .iter()
+        .map(|asset| Asset {
+            virtual_path: asset.virtual_path.clone(),
+            source: AssetSource::Local(dir.join(asset.virtual_path.trim_start_matches('/'))),
+        })
+        .collect::<Vec<_>>();
+    assert!(exported.iter().all(|asset| match &asset.source {
+        AssetSource::Local(path) => path.is_file(),
+        AssetSource::Remote(_) => false,
+    }));
+    let main = fs::read_to_string(dir.join("main.typ")).unwrap();
+    let bytes = compile_typst_to_pdf_with_options(
+        &main,
+        &CompileOptions {
+            assets: exported,
+            ..CompileOptions::default()
+        },
+        &dir.join("main.pdf"),
+    )
+    .expect("compile exported project")
+    .output;
+    assert_eq!(&bytes[..5], b"%PDF-");
+
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&cache_dir);
+}
+
+#[test]
+fn e2e_png_and_svg_page_export() {
+    let two_pages = "#let article(title: none, authors: (), lang: \"en\", toc: false, body) = {\n  body\n  pagebreak()\n  [Second page.]\n}";
+    let Prepared { source, .. } = md_to_typst(
+        "# Page one\n\nText.\n",
+        Pipeline {
+            template: Some(two_pages),
+            ..Pipeline::default()
+        },
+    );
+
+    let png = compile_typst_to_format(
+        &source,
+        &CompileOptions {
+            format: OutputFormat::Png,
+            ppi: Some(36.0),
+            ..CompileOptions::default()
+        },
+    )
+    .expect("png export")
+    .output;
+    assert_eq!(png.len(), 2);
+    assert!(png.iter().all(|page| page.starts_with(b"\x89PNG")));
+
+    let out_dir = std::env::temp_dir().join(format!("mdxport_pages_{}", std::process::id()));
+    let written = compile_typst_to_files(
+        &source,
+        &CompileOptions {
+            format: OutputFormat::Svg,
+            ..CompileOptions::default()
+        },
+        &out_dir.join("doc.svg"),
+    )
+    .expect("svg export")
+    .output;
+    let paths: Vec<_> = written.iter().map(|(path, _)| path.clone()).collect();
+    assert_eq!(
+        paths,
+        vec![out_dir.join("doc-1.svg"), out_dir.join("doc-2.svg")]
+    );
+    let svg = fs::read_to_string(&paths[0]).unwrap();
+    assert!(svg.contains("<svg"));
+    let _ = fs::remove_dir_all(&out_dir);
+
+    // A PDF is one buffer but still reports every page
+    let pdf = compile_typst_to_format(&source, &CompileOptions::default()).expect("pdf export");
+    assert_eq!((pdf.output.len(), pdf.pages), (1, 2));
+}
+
+#[test]
+fn page_output_paths_number_multi_page_output() {
+    assert_eq!(
+        page_output_paths(Path::new("out/doc.png"), 1),
+        vec![Path::new("out/doc.png").to_path_buf()]
+    );
+    assert_eq!(
+        page_output_paths(Path::new("out/doc.png"), 2),
+        vec![
+            Path::new("out/doc-1.png").to_path_buf(),
+            Path::new("out/doc-2.png").to_path_buf()
+        ]
+    );
+    assert_eq!(
+        OutputFormat::from_path(Path::new("a.SVG")),
+        Some(OutputFormat::Svg)
+    );
+    assert_eq!(OutputFormat::from_path(Path::new("a.txt")), None);
 }
 
 #[test]
@@ -237,6 +578,432 @@ fn e2e_high_level_api() {
     assert_eq!(&pdf[..5], b"%PDF-");
 }
 
+#[test]
+fn e2e_frontmatter_reaches_templates_as_meta() {
+    let md = "---\ntitle: Plan\ndepartment: R&D\nversion: 1.5\nclassification: {level: 2, tags: [internal]}\ndate: 2024-05-01\nreviewed: 2024-05-02\n---\n# Hello\n";
+    let custom = r#"#let mdxport-meta = true
+#let article(title: none, authors: (), lang: "en", toc: false, meta: (:), body) = {
+  assert.eq(meta.title, "Plan")
+  assert.eq(meta.department, "R&D")
+  assert.eq(meta.version, 1.5)
+  assert.eq(meta.classification.level, 2)
+  assert.eq(meta.classification.tags, ("internal",))
+  assert.eq(meta.date.display(), "2024-05-01")
+  assert.eq(meta.reviewed.display(), "2024-05-02")
+  [#meta.department v#meta.version]
+  body
+}"#;
+    for (style, custom_template) in [
+        (Style::ModernTech, Some(custom.into())),
+        (Style::ModernTech, None),
+        (Style::ClassicEditorial, None),
+    ] {
+        let pdf = mdxport::markdown_to_pdf(
+            md,
+            &mdxport::Options {
+                style,
+                custom_template,
+                ..mdxport::Options::default()
+            },
+        )
+        .expect("meta must be a valid Typst dictionary");
+        assert_eq!(&pdf[..5], b"%PDF-");
+    }
+}
+
+#[test]
+fn e2e_template_directories_get_meta_only_when_declared() {
+    let root = std::env::temp_dir().join(format!("mdxport_meta_dir_{}", std::process::id()));
+    let declared = root.join("declared");
+    let undeclared = root.join("undeclared");
+    for dir in [&declared, &undeclared] {
+        fs::create_dir_all(dir).unwrap();
+    }
+    // `article` comes from another file and takes `meta` through `..args`
+    fs::write(
+        declared.join("template.typ"),
+        "#import \"lib.typ\": article\n#let mdxport-meta = true\n",
+    )
+    .unwrap();
+    fs::write(
+        declared.join("lib.typ"),
+        "#let article(..args, body) = {\n  assert.eq(args.named().meta.department, \"Ops\")\n  body\n}\n",
+    )
+    .unwrap();
+    // No declaration: `meta` would be an unexpected argument
+    fs::write(
+        undeclared.join("template.typ"),
+        "#import \"lib.typ\": article\n",
+    )
+    .unwrap();
+    fs::write(
+        undeclared.join("lib.typ"),
+        "#let article(title: none, authors: (), lang: \"en\", toc: false, body) = body\n",
+    )
+    .unwrap();
+
+    for dir in [&declared, &undeclared] {
+        let pdf = mdxport::markdown_to_pdf(
+            "---\ndepartment: Ops\n---\n# Hello\n",
+            &mdxport::Options {
+                custom_template: Some(mdxport::CustomTemplate::load(dir).unwrap()),
+                ..mdxport::Options::default()
+            },
+        );
+        assert!(pdf.is_ok(), "{}: {:?}", dir.display(), pdf.err());
+    }
+    let _ = fs::remove_dir_all(&root);
+}
+
+#[test]
+fn e2e_pdf_document_metadata() {
+    let md = "---\ntitle: Quarterly Report\nauthor: Ada\nkeywords: [finance, q3]\nsubject: Q3 numbers\ndate: 2024-05-01\n---\n# Hello\n";
+    let custom = "#let article(title: none, authors: (), lang: \"en\", toc: false, body) = body";
+    for custom_template in [None, Some(custom.into())] {
+        let pdf = mdxport::markdown_to_pdf(
+            md,
+            &mdxport::Options {
+                custom_template,
+                ..mdxport::Options::default()
+            },
+        )
+        .expect("markdown_to_pdf should succeed");
+        let text = String::from_utf8_lossy(&pdf);
+        for expected in [
+            "/Title (Quarterly Report)",
+            "/Author (Ada)",
+            "/Keywords (finance, q3)",
+            "/Subject (Q3 numbers)",
+            "/CreationDate (D:20240501)",
+        ] {
+            assert!(text.contains(expected), "missing {expected}");
+        }
+    }
+}
+
+#[test]
+fn e2e_pdf_a_2b_output() {
+    let options = mdxport::Options {
+        pdf_standard: mdxport::PdfStandard::A2b,
+        ..mdxport::Options::default()
+    };
+    let pdf = mdxport::markdown_to_pdf("---\ntitle: Archive\n---\n# Hello\n\nWorld.\n", &options)
+        .expect("markdown_to_pdf should succeed");
+    let text = String::from_utf8_lossy(&pdf);
+    for marker in [
+        "<pdfaid:part>2</pdfaid:part>",
+        "<pdfaid:conformance>B</pdfaid:conformance>",
+        "/OutputIntents",
+        "/GTS_PDFA1",
+    ] {
+        assert!(text.contains(marker), "missing {marker}");
+    }
+
+    // An unassigned code point has no glyph: fine in plain PDF, not in PDF/A
+    let md = "# Missing glyph\n\n\u{0378}\n";
+    assert!(mdxport::markdown_to_pdf(md, &mdxport::Options::default()).is_ok());
+    let err = mdxport::markdown_to_pdf(md, &options)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("does not conform to PDF/A-2b"), "{err}");
+    assert!(
+        err.contains("could not be displayed with any font"),
+        "{err}"
+    );
+}
+
+#[test]
+fn pinned_timestamp_drives_today_and_reproducible_pdf() {
+    // 2024-05-01T00:00:00Z
+    let options = CompileOptions {
+        timestamp: Some(1_714_521_600),
+        ..CompileOptions::default()
+    };
+    let source = "#assert.eq(datetime.today(), datetime(year: 2024, month: 5, day: 1))\n#assert.eq(datetime.today(offset: -1), datetime(year: 2024, month: 4, day: 30))\nToday.";
+    mdxport::compile_typst_to_bytes(source, &options).expect("today() should be pinned");
+
+    let md = "---\ntitle: Reproducible\n---\n# Hello\n\nSame bytes every time.\n";
+    let options = mdxport::Options {
+        timestamp: Some(1_714_521_600),
+        ..mdxport::Options::default()
+    };
+    let first = mdxport::markdown_to_pdf(md, &options).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let second = mdxport::markdown_to_pdf(md, &options).unwrap();
+    assert!(first == second, "PDF output differs between runs");
+    assert!(String::from_utf8_lossy(&first).contains("/CreationDate (D:20240501000000Z)"));
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn cli_pdf_bytes_do_not_depend_on_how_the_input_path_is_spelled() {
+    let dir = std::env::temp_dir().join(format!("mdxport_ident_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("doc.md"), "# Hello\n\nSame bytes every time.\n").unwrap();
+
+    let absolute = dir.join("doc.md").display().to_string();
+    for (input, output) in [
+        ("doc.md", "a.pdf"),
+        ("./doc.md", "b.pdf"),
+        (&absolute, "c.pdf"),
+    ] {
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_mdxport"))
+            .current_dir(&dir)
+            .env("MDXPORT_NO_UPDATE_CHECK", "1")
+            .args([input, "--date", "2024-05-01", "-o", output])
+            .status()
+            .unwrap();
+        assert!(status.success(), "converting {input} failed");
+    }
+
+    let first = fs::read(dir.join("a.pdf")).unwrap();
+    assert!(
+        first == fs::read(dir.join("b.pdf")).unwrap(),
+        "./doc.md differs"
+    );
+    assert!(
+        first == fs::read(dir.join("c.pdf")).unwrap(),
+        "absolute path differs"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn cli_emit_typst_turns_a_file_output_into_a_project_directory() {
+    let dir = std::env::temp_dir().join(format!("mdxport_emit_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("doc.md"), "# Hello\n").unwrap();
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_mdxport"))
+        .current_dir(&dir)
+        .env("MDXPORT_NO_UPDATE_CHECK", "1")
+        .args(["doc.md", "--emit", "typst", "-o", "out.pdf"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(dir.join("out-typst").join("main.typ").is_file());
+    assert!(!dir.join("out.pdf").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn cli_parallel_runs_report_every_output_written_after_a_failure() {
+    let dir = std::env::temp_dir().join(format!("mdxport_jobs_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("bad.md"), "---\ntitle: [unclosed\n---\n# Bad\n").unwrap();
+    let good = ["a.md", "b.md", "c.md"];
+    for name in good {
+        fs::write(dir.join(name), "# Good\n").unwrap();
+    }
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_mdxport"))
+        .current_dir(&dir)
+        .env("MDXPORT_NO_UPDATE_CHECK", "1")
+        .args(["bad.md", "a.md", "b.md", "c.md", "--jobs", "4"])
+        .args(["--message-format", "json"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let summary: serde_json::Value = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .find(|line: &serde_json::Value| line["type"] == "summary")
+        .expect("no summary line");
+    let reported: Vec<&str> = summary["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["input"].as_str().unwrap())
+        .collect();
+    assert_eq!(reported.first(), Some(&"bad.md"));
+    for name in good {
+        let written = dir.join(name).with_extension("pdf").exists();
+        assert_eq!(
+            written,
+            reported.contains(&name),
+            "{name} written: {written}, reported: {reported:?}"
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn typst_warnings_are_reported_with_lines() {
+    let source = "= Title\n#set text(font: \"Nonexistent Sans\")\nHello.";
+    let compiled = mdxport::compile_typst_to_bytes(source, &CompileOptions::default()).unwrap();
+    assert_eq!(
+        compiled.warnings,
+        vec![Diagnostic {
+            typst_line: Some(2),
+            typst_snippet: Some("\"Nonexistent Sans\"".to_string()),
+            ..Diagnostic::new(
+                Stage::Compile,
+                Severity::Warning,
+                "unknown font family: nonexistent sans"
+            )
+        }]
+    );
+}
+
+#[test]
+fn typst_errors_map_back_to_markdown_positions() {
+    let markdown =
+        "---\ntitle: Mapped\n---\n# Heading\n\nSome text.\n\n- item $\\undefinedcmd{x}$ here\n";
+    let Prepared {
+        source, options, ..
+    } = md_to_typst(
+        markdown,
+        Pipeline {
+            compile: CompileOptions {
+                source_path: Some("notes.md".into()),
+                ..CompileOptions::default()
+            },
+            ..Pipeline::default()
+        },
+    );
+
+    let Err(CompileError::Typst(diagnostics)) = mdxport::compile_typst_to_bytes(&source, &options)
+    else {
+        panic!("expected a Typst error");
+    };
+    assert_eq!(diagnostics.len(), 1);
+    let diagnostic = &diagnostics[0];
+    assert_eq!(diagnostic.severity, Severity::Error);
+    assert_eq!(diagnostic.message, "unknown variable: undefinedcmd");
+    assert_eq!(
+        diagnostic.position,
+        Some(SourcePosition { line: 8, column: 9 })
+    );
+    assert_eq!(diagnostic.file.as_deref(), Some(Path::new("notes.md")));
+    assert!(
+        diagnostic.render("notes.md", markdown).contains(
+            " --> notes.md:8:9\n  |\n8 | - item $\\undefinedcmd{x}$ here\n  |         ^\n"
+        ),
+        "{}",
+        diagnostic.render("notes.md", markdown)
+    );
+}
+
+#[test]
+fn pipeline_errors_expose_staged_diagnostics() {
+    let error = mdxport::markdown_to_pdf("---\ntitle: [a\n---\nBody", &mdxport::Options::default())
+        .unwrap_err();
+    let diagnostics = error.diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].stage, Stage::Frontmatter);
+    assert_eq!(
+        diagnostics[0].position,
+        Some(SourcePosition { line: 2, column: 8 })
+    );
+
+    let error =
+        mdxport::markdown_to_pdf("Bad $\\undefinedcmd$", &mdxport::Options::default()).unwrap_err();
+    let diagnostics = error.diagnostics();
+    assert_eq!(diagnostics[0].stage, Stage::Compile);
+    assert_eq!(
+        diagnostics[0].position,
+        Some(SourcePosition { line: 1, column: 6 })
+    );
+    assert!(
+        serde_json::to_string(&diagnostics[0])
+            .unwrap()
+            .contains("\"stage\":\"compile\"")
+    );
+}
+
+#[test]
+fn builtin_style_font_fallbacks_are_not_warnings() {
+    for style in [Style::ModernTech, Style::ClassicEditorial] {
+        let source = compose_document(style, Some("T"), &[], "en", true, "Hello `code`.\n");
+        let compiled =
+            mdxport::compile_typst_to_bytes(&source, &CompileOptions::default()).unwrap();
+        assert!(compiled.warnings.is_empty(), "{:?}", compiled.warnings);
+    }
+}
+
+#[test]
+fn markdown_to_pdf_with_warnings_reports_every_stage() {
+    let options = mdxport::Options {
+        custom_template: Some(mdxport::CustomTemplate::from(
+            "#let article(title: none, authors: (), lang: \"en\", toc: false, body) = {\n  set text(font: \"Nonexistent Sans\")\n  body\n}\n",
+        )),
+        ..mdxport::Options::default()
+    };
+    let md = "---\ndate: 2024\n---\n![missing](missing.png)\n";
+    let compiled = mdxport::markdown_to_pdf_with_warnings(md, &options)
+        .expect("markdown_to_pdf_with_warnings should succeed");
+    assert!(compiled.output.starts_with(b"%PDF"));
+    assert_eq!(
+        compiled
+            .warnings
+            .iter()
+            .map(|warning| warning.stage)
+            .collect::<Vec<_>>(),
+        [Stage::Frontmatter, Stage::Convert, Stage::Compile],
+        "{:?}",
+        compiled.warnings
+    );
+}
+
+#[test]
+fn incremental_compiler_matches_one_shot_compiles() {
+    let options = CompileOptions {
+        timestamp: Some(1_714_521_600),
+        ..CompileOptions::default()
+    };
+    let mut compiler = IncrementalCompiler::new();
+    for body in [
+        "First version.\n",
+        "First version, edited.\n\n== Section\nMore.\n",
+        "Bad #undefinedcall()\n",
+        "Recovered.\n",
+    ] {
+        let source = compose_document(Style::ModernTech, Some("T"), &[], "en", false, body);
+        match (
+            compiler.compile(&source, &options),
+            mdxport::compile_typst_to_bytes(&source, &options),
+        ) {
+            (Ok(incremental), Ok(one_shot)) => {
+                assert!(incremental.output == vec![one_shot.output], "{body:?}");
+            }
+            (Err(CompileError::Typst(incremental)), Err(CompileError::Typst(one_shot))) => {
+                assert_eq!(incremental, one_shot);
+            }
+            (incremental, one_shot) => panic!(
+                "results differ for {body:?}: {:?} vs {:?}",
+                incremental.err(),
+                one_shot.err()
+            ),
+        }
+    }
+}
+
+#[test]
+fn e2e_high_level_api_concurrent_threads() {
+    let handles = (0..4)
+        .map(|i| {
+            std::thread::spawn(move || {
+                let md = format!("# Thread {i}\n\n{}", "Paragraph. ".repeat(20 * (i + 1)));
+                mdxport::markdown_to_pdf(&md, &mdxport::Options::default())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        let pdf = handle
+            .join()
+            .unwrap()
+            .expect("markdown_to_pdf should succeed");
+        assert_eq!(&pdf[..5], b"%PDF-");
+    }
+}
+
 #[test]
 fn e2e_write_and_verify_pdf_files() {
     // Generate actual PDFs to /tmp for visual inspection